@@ -1,5 +1,11 @@
-use crate::optimization::Optimization;
-use clap::Parser;
+use crate::error::Error;
+use crate::optimization::{
+    parse_color, read_palette, Assemble, ByteSize, Convert, Delay, Extract, ExtractMode,
+    Optimization, Options, Pack, PaletteFormat, Strategy, Timeout, DEF_QUALITY_MAX,
+};
+use crate::{EXIT_FAILURE, EXIT_INVALID_ARGS, EXIT_NOTHING_TO_DO, EXIT_SUCCESS};
+use clap::{Parser, Subcommand};
+use colored::*;
 use std::{env, path::PathBuf};
 
 #[derive(clap::ValueEnum, Clone, Debug)]
@@ -22,6 +28,7 @@ struct Args {
     #[arg(
         short = 's',
         long,
+        value_parser = clap::value_parser!(u8).range(1..=10),
         help = "1-10，更快的速度生成的图像质量更低，可用于实时生成图像。默认值为 4"
     )]
     speed: Option<u8>,
@@ -29,20 +36,66 @@ struct Args {
     #[arg(
         short = 'n',
         long,
-        help = "0-100，优化的最低质量，默认最低 0，不能高于最大值"
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        help = "0-100，优化的最低质量，默认最低 0，不能高于最大值，没有设置最大值时不能高于默认的 60"
     )]
     quality_min: Option<u8>,
 
     #[arg(
         short = 'x',
         long,
+        value_parser = clap::value_parser!(u8).range(0..=100),
         help = "0-100，优化的最大质量，默认最高100，不能低于最小值"
     )]
     quality_max: Option<u8>,
 
-    #[arg(short = 'd', long, help = "设置为1.0可获得漂亮的平滑图像，默认 1.0")]
+    #[arg(
+        short = 'd',
+        long,
+        value_parser = parse_fraction::<f32>,
+        help = "0-1，设置为1.0可获得漂亮的平滑图像，默认 1.0"
+    )]
     dithering_level: Option<f32>,
 
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u32).range(2..=256),
+        help = "2-256，调色板的最大颜色数量，不超过16个颜色时输出4位或更低位深的图像，默认 256"
+    )]
+    colors: Option<u32>,
+
+    #[arg(
+        long,
+        help = "单个文件压缩后的最大大小，例如 50K、1.5M，自动查找满足限制的最高质量，无法满足时保留原文件"
+    )]
+    max_bytes: Option<ByteSize>,
+
+    #[arg(
+        long,
+        help = "工作路径下所有文件压缩后的总大小，例如 5M，按文件的大小和复杂度分配质量，大而复杂的图像承担更多的压缩"
+    )]
+    total_bytes: Option<ByteSize>,
+
+    #[arg(
+        long,
+        value_parser = parse_fraction::<f64>,
+        help = "0-1，解码写入的文件与源图像比较多尺度结构相似度(SSIM)，低于阈值时恢复原文件，例如 0.98"
+    )]
+    min_ssim: Option<f64>,
+
+    #[arg(
+        long,
+        help = "输出每个图像源图像与优化后图像的逐像素误差热力图的文件夹，image.png 输出为 image.heatmap.png"
+    )]
+    diff_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "diff_dir",
+        help = "在 --diff-dir 中同时输出源图像和优化后图像的左右对比图 image.compare.png"
+    )]
+    side_by_side: bool,
+
     #[arg(
         short = 'c',
         long,
@@ -52,13 +105,226 @@ struct Args {
 
     #[arg(short = 'e', long, help = "压缩时需要排除的文件，传入PNG文件名")]
     exclude: Option<Vec<String>>,
+
+    #[arg(
+        short = 'g',
+        long,
+        help = "优化apng时同时导出使用相同调色板的同名gif动画，用于不支持apng的客户端"
+    )]
+    gif: bool,
+
+    #[arg(
+        long,
+        default_value_t = 128,
+        help = "0-255，导出gif时的alpha阈值，低于阈值的像素完全透明，其余像素完全不透明，默认 128"
+    )]
+    gif_alpha_threshold: u8,
+
+    #[arg(
+        long,
+        help = "工作路径下所有图像使用同一个共享调色板，用于运行时替换调色板的UI皮肤和瓦片集"
+    )]
+    shared_palette: bool,
+
+    #[arg(
+        long,
+        help = "使用调色板文件作为固定调色板，所有图像都重新映射到这个调色板，支持gpl、act、pal、json以及png色板"
+    )]
+    palette: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_parser = parse_color,
+        help = "必须保留在调色板中的固定颜色，格式为 #RRGGBBAA 或 #RRGGBB，可以传入多个"
+    )]
+    fixed_color: Vec<imagequant::RGBA>,
+
+    #[arg(
+        long,
+        help = "从调色板文件读取必须保留在调色板中的固定颜色，支持gpl、act、pal、json以及png色板"
+    )]
+    fixed_colors: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "同时导出优化后图像使用的调色板，image.png 导出为 image.palette.<格式>"
+    )]
+    export_palette: Option<PaletteFormat>,
+
+    #[arg(
+        short = 'j',
+        long,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "同时使用的线程数量，默认为系统的并行线程数。剩下的文件少于空闲线程时，大图像的重新映射分到多个线程执行"
+    )]
+    jobs: Option<u16>,
+
+    #[arg(
+        long,
+        help = "同时处理的文件估计占用的最大内存，例如 512M、2G，按 IHDR 的尺寸估计每个文件的内存，超出时等待其他文件处理完成，默认为系统可用内存"
+    )]
+    max_memory: Option<ByteSize>,
+
+    #[arg(
+        long,
+        conflicts_with = "shared_palette",
+        help = "读取工作路径下的 .png-palettes-optimization.journal 日志，跳过上次运行已经完成并且之后没有改变的文件，用于继续中断的运行。每次运行都记录完成的文件，全部完成后删除日志。共享调色板需要所有图像，不能与 --shared-palette 同时使用"
+    )]
+    resume: bool,
+
+    #[arg(
+        long,
+        help = "单个文件的最长处理时间，例如 30、500ms、2m，没有单位时为秒，超时的文件中止处理并保留原文件"
+    )]
+    timeout_per_file: Option<Timeout>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Strategy::Largest,
+        help = "文件开始处理的顺序，largest 按像素数量从大到小，directory 按扫描目录的顺序，random 随机顺序，默认 largest"
+    )]
+    schedule: Strategy,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
 }
 
-/// 处理命令行参数
-pub fn args_handle<'a>() {
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// 将apng的每一帧导出为单独的png图像，并生成记录帧信息的manifest.json
+    Extract {
+        #[arg(help = "要导出帧的apng文件路径")]
+        input: PathBuf,
+
+        #[arg(
+            short = 'o',
+            long,
+            help = "导出文件夹路径，默认apng同级的 <文件名>_frames 文件夹"
+        )]
+        output: Option<PathBuf>,
+
+        #[arg(
+            short = 'm',
+            long,
+            value_enum,
+            default_value_t = ExtractMode::Both,
+            help = "导出方式，raw 为fcTL描述的原始帧区域，composite 为合成后的完整画布，both 两种都导出"
+        )]
+        mode: ExtractMode,
+    },
+    /// 将多张png图像合成为使用共享调色板的apng，压缩参数使用全局的 -s -n -x -d -c
+    Assemble {
+        #[arg(help = "帧图像所在的文件夹，或者匹配帧图像的glob表达式，按文件名排序")]
+        input: String,
+
+        #[arg(short = 'o', long, help = "生成的apng文件路径")]
+        output: PathBuf,
+
+        #[arg(
+            short = 'D',
+            long,
+            default_value = "100",
+            help = "帧延时，逗号分隔，每项为毫秒数(100)或秒的分数(1/30)，帧数多于设置的数量时剩下的帧使用最后一项，默认 100"
+        )]
+        delay: Delay,
+
+        #[arg(
+            short = 'l',
+            long,
+            default_value_t = 0,
+            help = "循环次数，0为无限循环，默认 0"
+        )]
+        loops: u32,
+    },
+    /// 将gif动画转换为使用共享调色板的apng，压缩参数使用全局的 -s -n -x -d -c
+    Convert {
+        #[arg(help = "gif文件路径，传入文件夹时将转换文件夹下所有的gif文件")]
+        input: PathBuf,
+
+        #[arg(
+            short = 'o',
+            long,
+            help = "生成的apng文件路径，只在转换单个文件时有效，默认为gif同级的同名png文件"
+        )]
+        output: Option<PathBuf>,
+
+        #[arg(
+            short = 'l',
+            long,
+            help = "循环次数，0为无限循环，默认使用gif的循环次数"
+        )]
+        loops: Option<u32>,
+
+        #[arg(long, help = "覆盖已经存在的apng文件，默认跳过已经存在的文件")]
+        overwrite: bool,
+    },
+    /// 将文件夹中的小图打包为使用共享调色板的图集，并生成TexturePacker JSON (Hash)格式的图集描述，压缩参数使用全局的 -s -n -x -d -c
+    Pack {
+        #[arg(help = "小图所在的文件夹")]
+        input: PathBuf,
+
+        #[arg(
+            short = 'o',
+            long,
+            help = "图集输出文件夹，默认为输入文件夹同级的 <文件夹名>_atlas 文件夹"
+        )]
+        output: Option<PathBuf>,
+
+        #[arg(
+            long,
+            default_value = "atlas",
+            help = "图集文件名前缀，生成 <name>_0.png 和 <name>_0.json，默认 atlas"
+        )]
+        name: String,
+
+        #[arg(
+            short = 'm',
+            long,
+            default_value_t = 2048,
+            help = "单张图集的最大宽高，放不下时生成多张图集，默认 2048"
+        )]
+        max_size: u32,
+
+        #[arg(long, default_value_t = 1, help = "小图之间的间距，默认 1")]
+        padding: u32,
+    },
+}
+
+/// 解析`0-1`之间的小数，用于抖动等级和结构相似度阈值
+fn parse_fraction<T>(s: &str) -> Result<T, String>
+where
+    T: std::str::FromStr + PartialOrd + From<u8>,
+{
+    match s.trim().parse::<T>() {
+        Ok(value) if value >= T::from(0) && value <= T::from(1) => Ok(value),
+        _ => Err(format!("`{}` is not a number between 0 and 1", s)),
+    }
+}
+
+/// 处理命令行参数，返回进程退出码
+pub fn args_handle() -> u8 {
     // 获取命令行参数
     let args = Args::parse();
 
+    // 最低质量不能高于最大质量，没有设置最大质量时与默认值比较，
+    // 与clap的参数错误一样返回参数错误的退出码
+    if let Some(quality_min) = args.quality_min {
+        let quality_max = args.quality_max.unwrap_or(DEF_QUALITY_MAX);
+        if quality_min > quality_max {
+            eprintln!(
+                "{}",
+                format!(
+                    "quality_min {} is greater than quality_max {}",
+                    quality_min, quality_max
+                )
+                .red()
+            );
+            return EXIT_INVALID_ARGS;
+        }
+    }
+
     // 获取工作路径
     let path = if let Some(path) = args.path {
         path
@@ -74,17 +340,109 @@ pub fn args_handle<'a>() {
         _ => png::Compression::Best,
     };
 
+    // 读取固定调色板
+    let palette = match args.palette.as_deref().map(read_palette) {
+        Some(Ok(palette)) => Some(palette),
+        Some(Err(err)) => {
+            eprintln!("{}", err.to_string().red());
+            return EXIT_INVALID_ARGS;
+        }
+        None => None,
+    };
 
-    // 实例化优化结构体
-    let mut optimization = Optimization::new(
-        &path,
-        args.speed,
-        args.quality_min,
-        args.quality_max,
-        args.dithering_level,
+    // 读取固定颜色
+    let mut fixed_colors = args.fixed_color;
+    match args.fixed_colors.as_deref().map(read_palette) {
+        Some(Ok(colors)) => fixed_colors.extend(colors),
+        Some(Err(err)) => {
+            eprintln!("{}", err.to_string().red());
+            return EXIT_INVALID_ARGS;
+        }
+        None => {}
+    }
+
+    // 优化参数
+    let options = Options {
+        speed: args.speed,
+        quality_min: args.quality_min,
+        quality_max: args.quality_max,
+        dithering_level: args.dithering_level,
+        colors: args.colors,
         compression,
-        args.exclude,
-    );
+        gif: args.gif.then_some(args.gif_alpha_threshold),
+        shared_palette: args.shared_palette,
+        palette,
+        fixed_colors,
+        max_bytes: args.max_bytes.map(|size| size.0),
+        total_bytes: args.total_bytes.map(|size| size.0),
+        min_ssim: args.min_ssim,
+        diff_dir: args.diff_dir,
+        side_by_side: args.side_by_side,
+        export_palette: args.export_palette,
+        jobs: args.jobs.map(usize::from),
+        max_memory: args.max_memory.map(|size| size.0),
+        resume: args.resume,
+        timeout_per_file: args.timeout_per_file.map(|timeout| timeout.0),
+        deadline: None,
+        schedule: args.schedule,
+    };
+
+    if let Some(command) = args.command {
+        let result = match command {
+            Commands::Extract {
+                input,
+                output,
+                mode,
+            } => Extract::new(&input, output, mode, compression).run(),
+            Commands::Assemble {
+                input,
+                output,
+                delay,
+                loops,
+            } => Assemble::new(&input, &output, delay, loops, options).run(),
+            Commands::Convert {
+                input,
+                output,
+                loops,
+                overwrite,
+            } => Convert::new(&input, output, loops, overwrite, options).run(),
+            Commands::Pack {
+                input,
+                output,
+                name,
+                max_size,
+                padding,
+            } => Pack::new(&input, output, name, max_size, padding, options).run(),
+        };
+        return match result {
+            Ok(()) => EXIT_SUCCESS,
+            Err(err) => {
+                eprintln!("{}", err.to_string().red());
+                // 没有找到需要处理的文件时使用单独的退出码
+                let nothing_to_do = err
+                    .get_ref()
+                    .and_then(|err| err.downcast_ref::<Error>())
+                    .is_some_and(|err| matches!(err, Error::NoInputFiles));
+                if nothing_to_do {
+                    EXIT_NOTHING_TO_DO
+                } else {
+                    EXIT_FAILURE
+                }
+            }
+        };
+    }
+
+    // 工作路径必须是存在的文件夹
+    if !path.is_dir() {
+        eprintln!(
+            "{}",
+            format!("work path is not a directory: {}", path.display()).red()
+        );
+        return EXIT_INVALID_ARGS;
+    }
+
+    // 实例化优化结构体
+    let mut optimization = Optimization::new(&path, options, args.exclude);
     // 优化压缩png图像
-    optimization.quality();
+    optimization.quality()
 }
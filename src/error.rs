@@ -1,23 +1,164 @@
+use crate::interrupt;
 use std::fmt;
-pub use Error::*;
+use std::io;
+use std::path::{Path, PathBuf};
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Debug)]
 #[allow(non_camel_case_types)]
 pub enum Error {
     /// Congratulations, you've discovered an edge case
+    #[allow(dead_code)]
     Unsupported,
     /// 不支持的png颜色模式
     UnsupportedColorMode,
+    /// 不是apng图像
+    NotAnimated,
+    /// 没有找到需要处理的文件
+    NoInputFiles,
+    /// 帧图像尺寸不一致
+    FrameSizeMismatch,
+    /// 小图尺寸超过图集的最大尺寸
+    SpriteTooLarge,
+    /// 无法解析的调色板文件
+    InvalidPalette,
+    /// 画布尺寸超过gif支持的65535
+    GifTooLarge,
+    /// 重要性图与图像尺寸不一致
+    ImportanceMapSizeMismatch,
+    /// 最低质量和最少颜色都无法满足文件大小限制
+    OverBudget,
+    /// 结构相似度低于阈值
+    SimilarityTooLow,
+    /// 读写文件失败
+    Io(PathBuf, io::Error),
+    /// png解码失败
+    Decode(PathBuf, png::DecodingError),
+    /// 调色板生成或重新映射失败
+    Quantization(PathBuf, imagequant::Error),
+    /// png编码失败
+    Encode(PathBuf, png::EncodingError),
+    /// 执行工作任务的线程panic，参数为panic的信息
+    WorkerPanicked(String),
+    /// 收到中断信号，工作任务中止或者没有开始执行，原文件保持不变
+    Interrupted,
+    /// 处理时间超过`--timeout-per-file`，原文件保持不变
+    TimedOut,
+    /// 输出文件已经存在，没有设置覆盖
+    OutputExists(PathBuf),
+    /// 批量处理时有文件处理失败，参数为失败的文件数量
+    FilesFailed(usize),
 }
 
-impl std::error::Error for Error {}
+impl Error {
+    /// 读写`path`失败时附加文件路径，用于`map_err`
+    pub fn io(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+        move |err| Error::Io(path.to_path_buf(), err)
+    }
+
+    /// 解码`path`失败时附加文件路径，用于`map_err`
+    pub fn decode(path: &Path) -> impl FnOnce(png::DecodingError) -> Error + '_ {
+        move |err| Error::Decode(path.to_path_buf(), err)
+    }
+
+    /// 量化`path`失败时附加文件路径，用于`map_err`
+    ///
+    /// 进度回调中止量化时，收到中断信号的为中断，否则为超时
+    pub fn quantization(path: &Path) -> impl FnOnce(imagequant::Error) -> Error + '_ {
+        move |err| match err {
+            imagequant::Error::Aborted => Error::aborted(interrupt::is_interrupted()),
+            err => Error::Quantization(path.to_path_buf(), err),
+        }
+    }
+
+    /// 进度回调中止量化的错误，`interrupted`为收到了中断信号
+    fn aborted(interrupted: bool) -> Error {
+        if interrupted {
+            Error::Interrupted
+        } else {
+            Error::TimedOut
+        }
+    }
+
+    /// 编码`path`失败时附加文件路径，用于`map_err`
+    pub fn encode(path: &Path) -> impl FnOnce(png::EncodingError) -> Error + '_ {
+        move |err| Error::Encode(path.to_path_buf(), err)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(_, err) => Some(err),
+            Self::Decode(_, err) => Some(err),
+            Self::Quantization(_, err) => Some(err),
+            Self::Encode(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for Error {
     #[cold]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(match *self {
-            Self::Unsupported => "UNSUPPORTED",
-            Self::UnsupportedColorMode => "Unsupported_Color_Mode",
-        })
+        match self {
+            Self::Io(path, err) => write!(f, "IO_Error {}: {}", path.display(), err),
+            Self::Decode(path, err) => write!(f, "Decode_Error {}: {}", path.display(), err),
+            Self::Quantization(path, err) => {
+                write!(f, "Quantization_Error {}: {}", path.display(), err)
+            }
+            Self::Encode(path, err) => write!(f, "Encode_Error {}: {}", path.display(), err),
+            Self::WorkerPanicked(message) => write!(f, "Worker_Panicked: {}", message),
+            Self::OutputExists(path) => write!(f, "Output_Exists {}", path.display()),
+            Self::FilesFailed(num) => write!(f, "Files_Failed: {}", num),
+            Self::Unsupported => f.write_str("UNSUPPORTED"),
+            Self::UnsupportedColorMode => f.write_str("Unsupported_Color_Mode"),
+            Self::NotAnimated => f.write_str("Not_Animated"),
+            Self::NoInputFiles => f.write_str("No_Input_Files"),
+            Self::FrameSizeMismatch => f.write_str("Frame_Size_Mismatch"),
+            Self::SpriteTooLarge => f.write_str("Sprite_Too_Large"),
+            Self::InvalidPalette => f.write_str("Invalid_Palette"),
+            Self::GifTooLarge => f.write_str("Gif_Too_Large"),
+            Self::ImportanceMapSizeMismatch => f.write_str("Importance_Map_Size_Mismatch"),
+            Self::OverBudget => f.write_str("Over_Budget"),
+            Self::SimilarityTooLow => f.write_str("Similarity_Too_Low"),
+            Self::Interrupted => f.write_str("Interrupted"),
+            Self::TimedOut => f.write_str("Timed_Out"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn display_with_path() {
+        let path = Path::new("a/hero.png");
+        let err = Error::io(path)(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        assert_eq!(err.to_string(), "IO_Error a/hero.png: missing");
+        assert!(err.source().is_some());
+
+        let err = Error::quantization(path)(imagequant::Error::ValueOutOfRange);
+        assert!(err
+            .to_string()
+            .starts_with("Quantization_Error a/hero.png: "));
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn aborted_by_interrupt_or_timeout() {
+        assert!(matches!(Error::aborted(true), Error::Interrupted));
+        assert!(matches!(Error::aborted(false), Error::TimedOut));
+        // 中止以外的量化错误保留路径
+        let err = Error::quantization(Path::new("hero.png"))(imagequant::Error::OutOfMemory);
+        assert!(matches!(err, Error::Quantization(..)));
+    }
+
+    #[test]
+    fn display_without_source() {
+        assert_eq!(Error::OverBudget.to_string(), "Over_Budget");
+        assert_eq!(Error::FilesFailed(2).to_string(), "Files_Failed: 2");
+        assert!(Error::UnsupportedColorMode.source().is_none());
     }
 }
@@ -0,0 +1,33 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// 是否收到了中断信号
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// 注册`SIGINT`和`SIGTERM`的处理函数
+///
+/// 第一次收到信号时只记录中断，不再开始新的工作任务，正在执行的工作任务在进度回调中或者写入文件之前中止，
+/// 之后恢复默认的处理方式，再次收到信号时立即结束进程
+pub fn install() {
+    let handler = on_signal as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+}
+
+/// 是否收到了中断信号
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// 记录中断信号的进程全局状态，需要在回调中读取中断状态的地方传入，测试中可以替换为单独的状态
+pub fn flag() -> &'static AtomicBool {
+    &INTERRUPTED
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+    }
+}
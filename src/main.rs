@@ -1,5 +1,8 @@
+use std::process::ExitCode;
+
 mod command;
 mod error;
+mod interrupt;
 mod optimization;
 mod thread;
 
@@ -9,7 +12,17 @@ const BYTES_INTEGER: f64 = 1024.00;
 const PROGRESS_CONSTANT: f32 = 110.00;
 /// 转换毫秒为秒
 const SECOND_CONSTANT: f64 = 1000.00;
+/// 退出码，所有文件处理成功
+const EXIT_SUCCESS: u8 = 0;
+/// 退出码，有文件处理失败
+const EXIT_FAILURE: u8 = 1;
+/// 退出码，参数错误，与clap解析参数失败时的退出码一致
+const EXIT_INVALID_ARGS: u8 = 2;
+/// 退出码，没有需要处理的文件
+const EXIT_NOTHING_TO_DO: u8 = 3;
+/// 退出码，收到中断信号，与shell中进程被`SIGINT`结束时的退出码一致
+const EXIT_INTERRUPTED: u8 = 130;
 
-fn main() {
-    command::args_handle();
+fn main() -> ExitCode {
+    ExitCode::from(command::args_handle())
 }
@@ -0,0 +1,197 @@
+use super::image::{invalid_data, read_rgba_png};
+use super::pngquant::{quality_report, Progress};
+use super::{Frame, Options, Pngquant};
+use crate::error::Error;
+use crate::BYTES_INTEGER;
+use colored::*;
+use png::{BlendOp, DisposeOp};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// 帧延时设置，逗号分隔，每项为毫秒数(`100`)或秒的分数(`1/30`)
+///
+/// 帧数多于设置的数量时，剩下的帧使用最后一项延时
+#[derive(Clone, Debug)]
+pub struct Delay(Vec<(u16, u16)>);
+
+impl Delay {
+    /// 获取第`index`帧的延时
+    fn get(&self, index: usize) -> (u16, u16) {
+        self.0[index.min(self.0.len() - 1)]
+    }
+}
+
+impl FromStr for Delay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut delays = vec![];
+        for item in s.split(',').map(str::trim) {
+            let delay = match item.split_once('/') {
+                Some((num, den)) => (num.parse::<u16>(), den.parse::<u16>()),
+                None => (item.parse::<u16>(), Ok(1000)),
+            };
+            match delay {
+                (Ok(num), Ok(den)) if den > 0 => delays.push((num, den)),
+                _ => return Err(format!("invalid delay `{}`", item)),
+            }
+        }
+        Ok(Delay(delays))
+    }
+}
+
+/// 将多张png图像合成为使用共享调色板的apng
+#[derive(Debug)]
+pub struct Assemble<'a> {
+    /// 帧图像所在的文件夹，或者匹配帧图像的glob表达式
+    input: &'a str,
+    /// 生成的apng文件路径
+    output: &'a Path,
+    /// 帧延时
+    delay: Delay,
+    /// 循环次数，0为无限循环
+    num_plays: u32,
+    /// 优化参数
+    options: Options,
+}
+
+impl<'a> Assemble<'a> {
+    pub fn new(
+        input: &'a str,
+        output: &'a Path,
+        delay: Delay,
+        num_plays: u32,
+        options: Options,
+    ) -> Assemble<'a> {
+        Assemble {
+            input,
+            output,
+            delay,
+            num_plays,
+            options,
+        }
+    }
+
+    /// 按文件名排序获取所有帧图像路径
+    fn frame_paths(&self) -> io::Result<Vec<PathBuf>> {
+        let input = Path::new(self.input);
+        let mut paths: Vec<PathBuf> = if input.is_dir() {
+            fs::read_dir(input)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    path.extension()
+                        .and_then(OsStr::to_str)
+                        .is_some_and(|extension| extension.eq_ignore_ascii_case("png"))
+                })
+                .collect()
+        } else {
+            glob::glob(self.input)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+                .filter_map(Result::ok)
+                .collect()
+        };
+        paths.sort();
+        if paths.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, Error::NoInputFiles));
+        }
+        Ok(paths)
+    }
+
+    /// 读取帧图像，编码生成apng
+    pub fn run(&self) -> io::Result<()> {
+        let paths = self.frame_paths()?;
+        println!("assemble frames: {}", paths.len().to_string().green());
+
+        let mut frames: Vec<Frame> = Vec::with_capacity(paths.len());
+        let mut size: Option<(u32, u32)> = None;
+        let mut original_size = 0;
+        for (index, path) in paths.iter().enumerate() {
+            let (width, height, data) = read_rgba_png(path)?;
+            // 所有帧都作为完整画布写入，尺寸必须一致
+            match size {
+                Some(size) if size != (width, height) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: {}", path.display(), Error::FrameSizeMismatch),
+                    ))
+                }
+                _ => size = Some((width, height)),
+            }
+            original_size += fs::metadata(path)?.len();
+            let (delay_num, delay_den) = self.delay.get(index);
+            frames.push(Frame::new(
+                data,
+                width,
+                height,
+                0,
+                0,
+                delay_num,
+                delay_den,
+                DisposeOp::None,
+                BlendOp::Source,
+            ));
+        }
+        let (width, height) = size.unwrap();
+
+        // 输出调色板生成和编码的进度
+        let (progress_sender, progress) = Progress::printer();
+
+        let mut pngquant = Pngquant::from_frames(
+            0,
+            self.output,
+            frames,
+            width,
+            height,
+            self.num_plays,
+            &self.options,
+            progress_sender,
+            original_size,
+        )
+        .map_err(invalid_data)?;
+        pngquant.encoder(self.output).map_err(invalid_data)?;
+        let size = pngquant.size.unwrap_or(0);
+        let report = quality_report(pngquant.quality, pngquant.mse, pngquant.ssim);
+        // 关闭进度通道，等待进度输出结束
+        drop(pngquant);
+        progress.join().unwrap();
+
+        let change = format!(
+            "{}KB -> {}KB",
+            (original_size as f64 / BYTES_INTEGER).round(),
+            (size as f64 / BYTES_INTEGER).round()
+        );
+        println!("total file size change: {}", change.green());
+        println!("{}", report.green());
+        println!("output path: {}", self.output.display().to_string().green());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_milliseconds_and_fractions() {
+        let delay: Delay = "100, 1/30,0".parse().unwrap();
+        assert_eq!(delay.0, [(100, 1000), (1, 30), (0, 1000)]);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for s in ["", "abc", "1/0", "1/", "70000", "1,,2", "-1"] {
+            assert!(s.parse::<Delay>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn extra_frames_use_last_delay() {
+        let delay: Delay = "100,200".parse().unwrap();
+        assert_eq!(delay.get(0), (100, 1000));
+        assert_eq!(delay.get(1), (200, 1000));
+        assert_eq!(delay.get(5), (200, 1000));
+    }
+}
@@ -0,0 +1,304 @@
+use super::image::invalid_data;
+use super::pngquant::{quality_report, Progress};
+use super::{Frame, Options, Pngquant};
+use crate::error::Error;
+use crate::BYTES_INTEGER;
+use colored::*;
+use gif::{ColorOutput, DecodeOptions, DisposalMethod};
+use png::{BlendOp, DisposeOp};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// 将gif动画转换为使用共享调色板的apng
+#[derive(Debug)]
+pub struct Convert<'a> {
+    /// gif文件路径，或者包含gif文件的文件夹
+    path: &'a Path,
+    /// 生成的apng文件路径，只在转换单个文件时有效，默认为gif同级的同名png文件
+    output: Option<PathBuf>,
+    /// 循环次数，0为无限循环，`None`时使用gif的NETSCAPE循环次数
+    num_plays: Option<u32>,
+    /// 覆盖已经存在的apng文件
+    overwrite: bool,
+    /// 优化参数
+    options: Options,
+}
+
+impl<'a> Convert<'a> {
+    pub fn new(
+        path: &'a Path,
+        output: Option<PathBuf>,
+        num_plays: Option<u32>,
+        overwrite: bool,
+        options: Options,
+    ) -> Convert<'a> {
+        Convert {
+            path,
+            output,
+            num_plays,
+            overwrite,
+            options,
+        }
+    }
+
+    /// 遍历目录查找gif文件
+    fn visit_gifs(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                Convert::visit_gifs(&path, paths)?;
+            } else if path
+                .extension()
+                .and_then(OsStr::to_str)
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("gif"))
+            {
+                paths.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    /// 转换所有gif文件
+    pub fn run(&self) -> io::Result<()> {
+        if self.path.is_dir() {
+            println!("work Path: {}", self.path.display().to_string().green());
+            let mut paths = vec![];
+            Convert::visit_gifs(self.path, &mut paths)?;
+            if paths.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, Error::NoInputFiles));
+            }
+            paths.sort();
+            // 单个文件转换失败时输出原因并继续转换其余文件
+            let (mut convert_num, mut skip_num, mut failed_num) = (0, 0, 0);
+            for (id, path) in paths.iter().enumerate() {
+                let output = path.with_extension("png");
+                if output.exists() && !self.overwrite {
+                    skip_num += 1;
+                    println!(
+                        "skip existing file: {}",
+                        output.display().to_string().yellow()
+                    );
+                    continue;
+                }
+                match self.convert(id, path, &output) {
+                    Ok(()) => convert_num += 1,
+                    Err(err) => {
+                        failed_num += 1;
+                        println!(
+                            "failed file: {} {}",
+                            path.display().to_string().red(),
+                            err.to_string().red()
+                        );
+                    }
+                }
+            }
+            println!("convert the file: {}", convert_num.to_string().green());
+            if skip_num > 0 {
+                println!("skip the file: {}", skip_num.to_string().yellow());
+            }
+            if failed_num > 0 {
+                return Err(io::Error::other(Error::FilesFailed(failed_num)));
+            }
+        } else {
+            let output = self
+                .output
+                .clone()
+                .unwrap_or_else(|| self.path.with_extension("png"));
+            // 不覆盖已经存在的文件，例如 -g 导出gif时同级的apng
+            if output.exists() && !self.overwrite {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    Error::OutputExists(output),
+                ));
+            }
+            self.convert(0, self.path, &output)?;
+        }
+        Ok(())
+    }
+
+    /// 解码gif的所有帧，编码为apng写入`output`
+    fn convert(&self, id: usize, path: &Path, output: &Path) -> io::Result<()> {
+        println!("convert file: {}", path.display().to_string().green());
+        let original_size = fs::metadata(path)?.len();
+        let (width, height, frames) = decode_gif(path)?;
+        let num_plays = match self.num_plays {
+            Some(num_plays) => num_plays,
+            None => gif_num_plays(&fs::read(path)?),
+        };
+
+        // 输出调色板生成和编码的进度
+        let (progress_sender, progress) = Progress::printer();
+        let mut pngquant = Pngquant::from_frames(
+            id,
+            output,
+            frames,
+            width,
+            height,
+            num_plays,
+            &self.options,
+            progress_sender,
+            original_size,
+        )
+        .map_err(invalid_data)?;
+        pngquant.encoder(output).map_err(invalid_data)?;
+        let size = pngquant.size.unwrap_or(0);
+        let report = quality_report(pngquant.quality, pngquant.mse, pngquant.ssim);
+        // 关闭进度通道，等待进度输出结束
+        drop(pngquant);
+        progress.join().unwrap();
+
+        let change = format!(
+            "{}KB -> {}KB",
+            (original_size as f64 / BYTES_INTEGER).round(),
+            (size as f64 / BYTES_INTEGER).round()
+        );
+        println!("file size change: {}", change.green());
+        println!("{}", report.green());
+        Ok(())
+    }
+}
+
+/// 解码gif，返回画布的宽、高以及转换为apng语义的帧数据
+///
+/// gif的透明像素会显示之前的画面，所以除第一帧外都使用`BlendOp::Over`；
+/// apng第一帧必须覆盖整个画布，所以第一帧会合成到透明画布上
+fn decode_gif(path: &Path) -> io::Result<(u32, u32, Vec<Frame>)> {
+    let mut options = DecodeOptions::new();
+    // 解码为rgba，透明色索引的像素alpha为0
+    options.set_color_output(ColorOutput::RGBA);
+    let mut decoder = options.read_info(File::open(path)?).map_err(invalid_data)?;
+    let width = decoder.width() as u32;
+    let height = decoder.height() as u32;
+
+    let mut frames: Vec<Frame> = vec![];
+    while let Some(frame) = decoder.read_next_frame().map_err(invalid_data)? {
+        let dispose_op = match frame.dispose {
+            DisposalMethod::Any | DisposalMethod::Keep => DisposeOp::None,
+            DisposalMethod::Background => DisposeOp::Background,
+            DisposalMethod::Previous => DisposeOp::Previous,
+        };
+        // gif的延时单位是百分之一秒
+        let (delay_num, delay_den) = (frame.delay, 100);
+
+        if frames.is_empty() {
+            let data = place_on_canvas(
+                &frame.buffer,
+                frame.left as u32,
+                frame.top as u32,
+                frame.width as u32,
+                frame.height as u32,
+                width,
+                height,
+            );
+            frames.push(Frame::new(
+                data,
+                width,
+                height,
+                0,
+                0,
+                delay_num,
+                delay_den,
+                dispose_op,
+                BlendOp::Source,
+            ));
+            continue;
+        }
+
+        // 超出画布的帧区域在apng中不合法，裁剪到画布范围内
+        let left = frame.left as u32;
+        let top = frame.top as u32;
+        let columns = (frame.width as u32).min(width.saturating_sub(left));
+        let rows = (frame.height as u32).min(height.saturating_sub(top));
+        let (data, x_offset, y_offset, frame_width, frame_height, dispose_op) =
+            if columns == 0 || rows == 0 {
+                // 完全在画布外的帧，用一个透明像素代替，不处置避免清除画布左上角的像素
+                (vec![0; 4], 0, 0, 1, 1, DisposeOp::None)
+            } else {
+                let mut data = Vec::with_capacity(columns as usize * rows as usize * 4);
+                for y in 0..rows as usize {
+                    let start = y * frame.width as usize * 4;
+                    data.extend_from_slice(&frame.buffer[start..start + columns as usize * 4]);
+                }
+                (data, left, top, columns, rows, dispose_op)
+            };
+        frames.push(Frame::new(
+            data,
+            frame_width,
+            frame_height,
+            x_offset,
+            y_offset,
+            delay_num,
+            delay_den,
+            dispose_op,
+            BlendOp::Over,
+        ));
+    }
+
+    if frames.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            Error::NotAnimated,
+        ));
+    }
+    Ok((width, height, frames))
+}
+
+/// 读取gif的NETSCAPE2.0应用扩展中的循环次数，转换为apng的播放次数
+///
+/// 没有扩展时只播放一次；循环次数0为无限循环，对应apng的0；
+/// 其他值与浏览器一致，第一次播放之后再重复指定的次数
+fn gif_num_plays(data: &[u8]) -> u32 {
+    const NETSCAPE: &[u8] = b"\x21\xff\x0bNETSCAPE2.0\x03\x01";
+    let loops = data
+        .windows(NETSCAPE.len() + 2)
+        .find(|window| window.starts_with(NETSCAPE))
+        .map(|window| u16::from_le_bytes([window[NETSCAPE.len()], window[NETSCAPE.len() + 1]]));
+    match loops {
+        None => 1,
+        Some(0) => 0,
+        Some(loops) => loops as u32 + 1,
+    }
+}
+
+/// 将帧区域的rgba数据放到完全透明的画布上
+fn place_on_canvas(
+    data: &[u8],
+    left: u32,
+    top: u32,
+    frame_width: u32,
+    frame_height: u32,
+    width: u32,
+    height: u32,
+) -> Vec<u8> {
+    let mut canvas = vec![0; width as usize * height as usize * 4];
+    for y in 0..frame_height.min(height.saturating_sub(top)) as usize {
+        let columns = frame_width.min(width.saturating_sub(left)) as usize;
+        let src = y * frame_width as usize * 4;
+        let dst = ((top as usize + y) * width as usize + left as usize) * 4;
+        canvas[dst..dst + columns * 4].copy_from_slice(&data[src..src + columns * 4]);
+    }
+    canvas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn netscape(loops: u16) -> Vec<u8> {
+        let mut data = b"GIF89a\x21\xff\x0bNETSCAPE2.0\x03\x01".to_vec();
+        data.extend_from_slice(&loops.to_le_bytes());
+        data.push(0);
+        data
+    }
+
+    #[test]
+    fn gif_loops_to_num_plays() {
+        assert_eq!(gif_num_plays(&netscape(0)), 0);
+        assert_eq!(gif_num_plays(&netscape(2)), 3);
+        assert_eq!(gif_num_plays(&netscape(u16::MAX)), 65536);
+        // 没有NETSCAPE扩展时只播放一次
+        assert_eq!(gif_num_plays(b"GIF89a"), 1);
+    }
+}
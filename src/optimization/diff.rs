@@ -0,0 +1,97 @@
+/// 像素误差达到这个值时热力图为最亮的颜色
+const MAX_ERROR: f32 = 64.0;
+
+/// 计算两张rgba图像每个像素的误差，rgb预乘alpha后与alpha通道一起计算均方根误差
+pub fn pixel_errors(a: &[u8], b: &[u8]) -> Vec<f32> {
+    a.chunks_exact(4)
+        .zip(b.chunks_exact(4))
+        .map(|(a, b)| {
+            let (alpha_a, alpha_b) = (a[3] as f32 / 255.0, b[3] as f32 / 255.0);
+            let mut sum = 0.0;
+            for channel in 0..3 {
+                let diff = a[channel] as f32 * alpha_a - b[channel] as f32 * alpha_b;
+                sum += diff * diff;
+            }
+            let diff = a[3] as f32 - b[3] as f32;
+            sum += diff * diff;
+            (sum / 4.0).sqrt()
+        })
+        .collect()
+}
+
+/// 将像素误差转换为热力图的rgba数据，误差从小到大依次为黑、红、黄、白
+pub fn heatmap(errors: &[f32]) -> Vec<u8> {
+    errors
+        .iter()
+        .flat_map(|&error| {
+            let t = (error / MAX_ERROR).min(1.0) * 3.0;
+            let channel = |offset: f32| ((t - offset).clamp(0.0, 1.0) * 255.0) as u8;
+            [channel(0.0), channel(1.0), channel(2.0), 255]
+        })
+        .collect()
+}
+
+/// 将两张相同尺寸的rgba图像左右拼接，左边为源图像，右边为优化后的图像
+pub fn side_by_side(width: u32, height: u32, source: &[u8], result: &[u8]) -> Vec<u8> {
+    let row = width as usize * 4;
+    let mut data = Vec::with_capacity(row * 2 * height as usize);
+    for y in 0..height as usize {
+        data.extend_from_slice(&source[y * row..(y + 1) * row]);
+        data.extend_from_slice(&result[y * row..(y + 1) * row]);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_pixel_errors() {
+        let a = [10, 20, 30, 255, 0, 0, 0, 255, 200, 0, 0, 0];
+        let b = [10, 20, 30, 255, 8, 8, 8, 255, 0, 200, 0, 0];
+        let errors = pixel_errors(&a, &b);
+        assert_eq!(errors.len(), 3);
+        // 相同的像素没有误差
+        assert_eq!(errors[0], 0.0);
+        // rgb各差8，alpha相同
+        assert!((errors[1] - (3.0 * 64.0 / 4.0f32).sqrt()).abs() < 1e-4);
+        // 完全透明的像素颜色不同时没有误差
+        assert_eq!(errors[2], 0.0);
+        // 只有alpha不同时同样计算误差
+        let errors = pixel_errors(&[0, 0, 0, 255], &[0, 0, 0, 55]);
+        assert!((errors[0] - 100.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn heatmap_ramp() {
+        let colors = heatmap(&[
+            0.0,
+            MAX_ERROR / 3.0,
+            MAX_ERROR * 2.0 / 3.0,
+            MAX_ERROR,
+            1000.0,
+        ]);
+        // 黑、红、黄、白，超出最大误差时仍然为白色
+        assert_eq!(&colors[0..4], [0, 0, 0, 255]);
+        assert_eq!(&colors[4..8], [255, 0, 0, 255]);
+        assert_eq!(&colors[8..12], [255, 255, 0, 255]);
+        assert_eq!(&colors[12..16], [255, 255, 255, 255]);
+        assert_eq!(&colors[16..20], [255, 255, 255, 255]);
+        // 中间的误差在相邻颜色之间过渡
+        let half = heatmap(&[MAX_ERROR / 6.0]);
+        assert_eq!(half, [127, 0, 0, 255]);
+    }
+
+    #[test]
+    fn side_by_side_rows() {
+        // 2x2的图像，每行的像素各不相同
+        let source = [[1; 8], [3; 8]].concat();
+        let result = [[2; 8], [4; 8]].concat();
+        let data = side_by_side(2, 2, &source, &result);
+        // 宽度为两倍，高度不变
+        assert_eq!(data.len(), 4 * 2 * 2 * 2);
+        // 每行左边为源图像的这一行，右边为优化后图像的这一行
+        assert_eq!(data, [[1; 8], [2; 8], [3; 8], [4; 8]].concat());
+    }
+}
@@ -0,0 +1,191 @@
+use super::frame::Canvas;
+use super::image::{expand_rgba, write_rgba_png};
+use super::Frame;
+use crate::error::Error;
+use colored::*;
+use png::{BlendOp, Compression, Decoder, DisposeOp, Transformations};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// apng帧导出方式
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ExtractMode {
+    /// 只导出`fcTL`描述的原始帧区域
+    Raw,
+    /// 只导出合成后的完整画布
+    Composite,
+    /// 两种都导出
+    Both,
+}
+
+/// 将apng的每一帧导出为单独的png图像
+#[derive(Debug)]
+pub struct Extract<'a> {
+    /// apng文件路径
+    path: &'a Path,
+    /// 导出文件夹
+    output: PathBuf,
+    /// 导出方式
+    mode: ExtractMode,
+    /// png编码压缩等级
+    compression: Compression,
+}
+
+/// 导出清单，保存为`manifest.json`
+#[derive(Serialize, Debug)]
+struct Manifest {
+    width: u32,
+    height: u32,
+    num_frames: u32,
+    /// 循环次数，0为无限循环
+    num_plays: u32,
+    frames: Vec<FrameManifest>,
+}
+
+#[derive(Serialize, Debug)]
+struct FrameManifest {
+    index: usize,
+    width: u32,
+    height: u32,
+    x_offset: u32,
+    y_offset: u32,
+    delay_num: u16,
+    delay_den: u16,
+    dispose_op: &'static str,
+    blend_op: &'static str,
+    /// 原始帧文件，相对于导出文件夹
+    raw: Option<String>,
+    /// 合成帧文件，相对于导出文件夹
+    composite: Option<String>,
+}
+
+impl<'a> Extract<'a> {
+    pub fn new(
+        path: &'a Path,
+        output: Option<PathBuf>,
+        mode: ExtractMode,
+        compression: Compression,
+    ) -> Extract<'a> {
+        // 默认导出到apng同级的`<文件名>_frames`文件夹
+        let output = output.unwrap_or_else(|| {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            path.with_file_name(format!("{}_frames", stem))
+        });
+        Extract {
+            path,
+            output,
+            mode,
+            compression,
+        }
+    }
+
+    /// 导出所有帧以及清单文件
+    pub fn run(&self) -> io::Result<()> {
+        println!("extract file: {}", self.path.display().to_string().green());
+
+        let mut decoder = Decoder::new(File::open(self.path)?);
+        // 帧数据按rgba处理，调色板等颜色模式需要先展开
+        decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+        let mut reader = decoder.read_info()?;
+        let (color_type, _) = reader.output_color_type();
+        let info = reader.info();
+        let (width, height) = (info.width, info.height);
+        let animation = info
+            .animation_control
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, Error::NotAnimated))?;
+
+        let mut frames = Frame::read_frames(&mut reader)?;
+        for frame in frames.iter_mut() {
+            frame.data = expand_rgba(color_type, std::mem::take(&mut frame.data))?;
+        }
+
+        let raw = matches!(self.mode, ExtractMode::Raw | ExtractMode::Both);
+        let composite = matches!(self.mode, ExtractMode::Composite | ExtractMode::Both);
+        if raw {
+            fs::create_dir_all(self.output.join("raw"))?;
+        }
+        if composite {
+            fs::create_dir_all(self.output.join("composite"))?;
+        }
+
+        let mut canvas = Canvas::new(width, height);
+        let mut manifest = Manifest {
+            width,
+            height,
+            num_frames: animation.num_frames,
+            num_plays: animation.num_plays,
+            frames: Vec::with_capacity(frames.len()),
+        };
+
+        for (index, frame) in frames.iter().enumerate() {
+            let file_name = format!("frame_{:04}.png", index);
+
+            let raw_name = if raw {
+                let name = format!("raw/{}", file_name);
+                write_rgba_png(
+                    &self.output.join(&name),
+                    frame.width,
+                    frame.height,
+                    &frame.data,
+                    self.compression,
+                )?;
+                Some(name)
+            } else {
+                None
+            };
+
+            let composite_name = if composite {
+                let name = format!("composite/{}", file_name);
+                let data = canvas.render(frame);
+                write_rgba_png(
+                    &self.output.join(&name),
+                    width,
+                    height,
+                    &data,
+                    self.compression,
+                )?;
+                Some(name)
+            } else {
+                None
+            };
+
+            manifest.frames.push(FrameManifest {
+                index,
+                width: frame.width,
+                height: frame.height,
+                x_offset: frame.x_offset,
+                y_offset: frame.y_offset,
+                delay_num: frame.delay_num,
+                delay_den: frame.delay_den,
+                dispose_op: dispose_op_name(frame.dispose_op),
+                blend_op: blend_op_name(frame.blend_op),
+                raw: raw_name,
+                composite: composite_name,
+            });
+        }
+
+        let file = File::create(self.output.join("manifest.json"))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &manifest)?;
+
+        println!("extract frames: {}", frames.len().to_string().green());
+        println!("output path: {}", self.output.display().to_string().green());
+        Ok(())
+    }
+}
+
+fn dispose_op_name(dispose_op: DisposeOp) -> &'static str {
+    match dispose_op {
+        DisposeOp::None => "none",
+        DisposeOp::Background => "background",
+        DisposeOp::Previous => "previous",
+    }
+}
+
+fn blend_op_name(blend_op: BlendOp) -> &'static str {
+    match blend_op {
+        BlendOp::Source => "source",
+        BlendOp::Over => "over",
+    }
+}
@@ -1,4 +1,5 @@
-use png;
+use png::{BlendOp, DecodingError, DisposeOp, Reader};
+use std::io::Read;
 
 #[derive(Debug)]
 pub struct Frame {
@@ -15,6 +16,7 @@ pub struct Frame {
 }
 
 impl Frame {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         data: Vec<u8>,
         width: u32,
@@ -39,4 +41,218 @@ impl Frame {
             pixels: None,
         }
     }
+
+    /// 循环读取apng的所有帧数据，没有`fcTL`的默认图像将跳过
+    ///
+    /// 按`acTL`记录的帧数读取，帧数据缺失或损坏时返回解码错误
+    pub fn read_frames<R: Read>(reader: &mut Reader<R>) -> Result<Vec<Frame>, DecodingError> {
+        let num_frames = reader
+            .info()
+            .animation_control
+            .map_or(0, |animation| animation.num_frames) as usize;
+        let mut frames: Vec<Frame> = Vec::with_capacity(num_frames);
+        while frames.len() < num_frames {
+            let mut buf = vec![0; reader.output_buffer_size()];
+            let output = reader.next_frame(&mut buf)?;
+            let info = reader.info();
+            if let Some(control) = info.frame_control() {
+                buf.truncate(output.buffer_size());
+                // 将每帧数据保存
+                frames.push(Frame::new(
+                    buf,
+                    control.width,
+                    control.height,
+                    control.x_offset,
+                    control.y_offset,
+                    control.delay_num,
+                    control.delay_den,
+                    control.dispose_op,
+                    control.blend_op,
+                ));
+            }
+        }
+        Ok(frames)
+    }
+}
+
+/// apng画布，按照`dispose_op`和`blend_op`把每帧合成为完整的图像
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    /// 当前画布的rgba数据
+    pub data: Vec<u8>,
+    /// 是否是第一帧，第一帧的`DisposeOp::Previous`按`DisposeOp::Background`处理
+    first: bool,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Canvas {
+        Canvas {
+            width,
+            height,
+            data: vec![0; width as usize * height as usize * 4],
+            first: true,
+        }
+    }
+
+    /// 合成一帧，返回合成后的完整画布，之后再按照帧的`dispose_op`处理画布
+    pub fn render(&mut self, frame: &Frame) -> Vec<u8> {
+        let previous = match frame.dispose_op {
+            DisposeOp::Previous if !self.first => Some(self.data.clone()),
+            _ => None,
+        };
+
+        let stride = self.width as usize * 4;
+        for y in 0..frame.height as usize {
+            let canvas_y = frame.y_offset as usize + y;
+            if canvas_y >= self.height as usize {
+                break;
+            }
+            for x in 0..frame.width as usize {
+                let canvas_x = frame.x_offset as usize + x;
+                if canvas_x >= self.width as usize {
+                    break;
+                }
+                let src = (y * frame.width as usize + x) * 4;
+                let dst = canvas_y * stride + canvas_x * 4;
+                let src = &frame.data[src..src + 4];
+                match frame.blend_op {
+                    BlendOp::Source => self.data[dst..dst + 4].copy_from_slice(src),
+                    BlendOp::Over => blend_over(&mut self.data[dst..dst + 4], src),
+                }
+            }
+        }
+
+        let output = self.data.clone();
+
+        match frame.dispose_op {
+            DisposeOp::None => {}
+            DisposeOp::Background => self.clear(frame),
+            DisposeOp::Previous => match previous {
+                Some(previous) => self.data = previous,
+                None => self.clear(frame),
+            },
+        }
+        self.first = false;
+
+        output
+    }
+
+    /// 将帧所在的区域清空为完全透明
+    fn clear(&mut self, frame: &Frame) {
+        let stride = self.width as usize * 4;
+        let right = ((frame.x_offset + frame.width).min(self.width)) as usize;
+        let bottom = ((frame.y_offset + frame.height).min(self.height)) as usize;
+        for y in frame.y_offset as usize..bottom {
+            let start = y * stride + frame.x_offset as usize * 4;
+            let end = y * stride + right * 4;
+            self.data[start..end].fill(0);
+        }
+    }
+}
+
+/// 按照alpha通道将`src`像素混合到`dst`像素上
+fn blend_over(dst: &mut [u8], src: &[u8]) {
+    let src_a = src[3] as u32;
+    if src_a == 255 {
+        dst.copy_from_slice(src);
+        return;
+    }
+    if src_a == 0 {
+        return;
+    }
+    let dst_a = dst[3] as u32;
+    // 输出的alpha值，放大255倍保留精度
+    let out_a = src_a * 255 + dst_a * (255 - src_a);
+    for i in 0..3 {
+        let value = (src[i] as u32 * src_a * 255 + dst[i] as u32 * dst_a * (255 - src_a)) / out_a;
+        dst[i] = value as u8;
+    }
+    dst[3] = ((out_a + 127) / 255) as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+    fn frame(
+        pixels: &[[u8; 4]],
+        width: u32,
+        x_offset: u32,
+        dispose_op: DisposeOp,
+        blend_op: BlendOp,
+    ) -> Frame {
+        Frame::new(
+            pixels.concat(),
+            width,
+            pixels.len() as u32 / width,
+            x_offset,
+            0,
+            1,
+            10,
+            dispose_op,
+            blend_op,
+        )
+    }
+
+    #[test]
+    fn blend_source_and_over() {
+        let mut canvas = Canvas::new(2, 1);
+        let first = frame(&[RED, RED], 2, 0, DisposeOp::None, BlendOp::Source);
+        assert_eq!(canvas.render(&first), [RED, RED].concat());
+
+        // Over的透明像素保留画布，Source的透明像素清除画布
+        let over = frame(&[CLEAR, BLUE], 2, 0, DisposeOp::None, BlendOp::Over);
+        assert_eq!(canvas.render(&over), [RED, BLUE].concat());
+        let source = frame(&[CLEAR], 1, 1, DisposeOp::None, BlendOp::Source);
+        assert_eq!(canvas.render(&source), [RED, CLEAR].concat());
+    }
+
+    #[test]
+    fn blend_over_half_alpha() {
+        let mut canvas = Canvas::new(1, 1);
+        canvas.render(&frame(&[BLUE], 1, 0, DisposeOp::None, BlendOp::Source));
+        let output = canvas.render(&frame(
+            &[[255, 0, 0, 128]],
+            1,
+            0,
+            DisposeOp::None,
+            BlendOp::Over,
+        ));
+        assert_eq!(output, [128, 0, 127, 255]);
+    }
+
+    #[test]
+    fn dispose_background_and_previous() {
+        let mut canvas = Canvas::new(2, 1);
+        canvas.render(&frame(&[RED, RED], 2, 0, DisposeOp::None, BlendOp::Source));
+
+        let background = frame(&[BLUE], 1, 0, DisposeOp::Background, BlendOp::Source);
+        assert_eq!(canvas.render(&background), [BLUE, RED].concat());
+        assert_eq!(canvas.data, [CLEAR, RED].concat());
+
+        let previous = frame(&[BLUE], 1, 1, DisposeOp::Previous, BlendOp::Source);
+        assert_eq!(canvas.render(&previous), [CLEAR, BLUE].concat());
+        assert_eq!(canvas.data, [CLEAR, RED].concat());
+    }
+
+    #[test]
+    fn first_frame_previous_clears() {
+        let mut canvas = Canvas::new(1, 1);
+        let first = frame(&[RED], 1, 0, DisposeOp::Previous, BlendOp::Source);
+        assert_eq!(canvas.render(&first), RED);
+        assert_eq!(canvas.data, CLEAR);
+    }
+
+    #[test]
+    fn frame_outside_canvas_is_clipped() {
+        let mut canvas = Canvas::new(2, 1);
+        let wide = frame(&[RED, BLUE], 2, 1, DisposeOp::Background, BlendOp::Source);
+        assert_eq!(canvas.render(&wide), [CLEAR, RED].concat());
+        assert_eq!(canvas.data, [CLEAR, CLEAR].concat());
+    }
 }
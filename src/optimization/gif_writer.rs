@@ -0,0 +1,246 @@
+use super::Frame;
+use crate::error::Error;
+use gif::{DisposalMethod, Encoder, Repeat};
+use png::{BlendOp, DisposeOp};
+use std::{fs::File, io, io::BufWriter, path::Path};
+
+/// 使用apng的共享调色板和索引数据写入gif动画
+///
+/// gif只支持一个完全透明的颜色，alpha低于`alpha_threshold`的调色板颜色都映射到同一个透明索引。
+/// gif的透明像素总是显示之前的画面，无法表示`BlendOp::Source`帧清除画布，
+/// 所以按apng的`blend_op`和`dispose_op`在索引画布上合成每帧，每帧写入完整的画布并在显示后恢复为背景。
+/// 画布尺寸超过65535时返回错误
+pub fn write_gif(
+    path: &Path,
+    width: u32,
+    height: u32,
+    palette: &[imagequant::RGBA],
+    frames: &[Frame],
+    num_plays: u32,
+    alpha_threshold: u8,
+) -> io::Result<()> {
+    let to_io_error = |err: gif::EncodingError| io::Error::other(err);
+    let (Ok(gif_width), Ok(gif_height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            Error::GifTooLarge,
+        ));
+    };
+
+    let transparent = palette.iter().position(|color| color.a < alpha_threshold);
+    let global_palette: Vec<u8> = palette
+        .iter()
+        .flat_map(|color| [color.r, color.g, color.b])
+        .collect();
+
+    let file = File::create(path)?;
+    let w = &mut BufWriter::new(file);
+    let mut encoder =
+        Encoder::new(w, gif_width, gif_height, &global_palette).map_err(to_io_error)?;
+
+    // apng的循环次数包含第一次播放，gif的循环次数是第一次播放之后重复的次数
+    match num_plays {
+        0 => encoder.set_repeat(Repeat::Infinite).map_err(to_io_error)?,
+        1 => {}
+        n => encoder
+            .set_repeat(Repeat::Finite((n - 1).min(u16::MAX as u32) as u16))
+            .map_err(to_io_error)?,
+    }
+
+    let mut canvas = IndexedCanvas::new(width, height, transparent.map_or(0, |index| index as u8));
+    for frame in frames.iter() {
+        if let Some(pixels) = &frame.pixels {
+            let buffer = canvas.render(frame, pixels, |index| {
+                (palette[index as usize].a < alpha_threshold).then_some(transparent)?
+            });
+
+            let mut gif_frame = gif::Frame {
+                width: gif_width,
+                height: gif_height,
+                delay: delay_centiseconds(frame.delay_num, frame.delay_den),
+                // 每帧都是完整的画布，显示后清除，下一帧的透明像素不会显示之前的画面
+                dispose: DisposalMethod::Background,
+                transparent: transparent.map(|index| index as u8),
+                ..gif::Frame::default()
+            };
+            gif_frame.buffer = buffer.into();
+            encoder.write_frame(&gif_frame).map_err(to_io_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// 使用调色板索引的apng画布，背景为透明索引
+struct IndexedCanvas {
+    width: u32,
+    height: u32,
+    /// 透明索引，调色板没有透明颜色时为0
+    background: u8,
+    /// 当前画布的索引数据
+    data: Vec<u8>,
+    /// 是否是第一帧，第一帧的`DisposeOp::Previous`按`DisposeOp::Background`处理
+    first: bool,
+}
+
+impl IndexedCanvas {
+    fn new(width: u32, height: u32, background: u8) -> IndexedCanvas {
+        IndexedCanvas {
+            width,
+            height,
+            background,
+            data: vec![background; width as usize * height as usize],
+            first: true,
+        }
+    }
+
+    /// 合成一帧的索引数据，返回合成后的完整画布，之后再按照帧的`dispose_op`处理画布
+    ///
+    /// `transparent`返回需要改为透明索引的颜色，`BlendOp::Over`时这些像素保留画布原有的索引
+    fn render(
+        &mut self,
+        frame: &Frame,
+        pixels: &[u8],
+        transparent: impl Fn(u8) -> Option<usize>,
+    ) -> Vec<u8> {
+        let previous = match frame.dispose_op {
+            DisposeOp::Previous if !self.first => Some(self.data.clone()),
+            _ => None,
+        };
+
+        let right = (frame.x_offset + frame.width).min(self.width);
+        let bottom = (frame.y_offset + frame.height).min(self.height);
+        for canvas_y in frame.y_offset..bottom {
+            for canvas_x in frame.x_offset..right {
+                let src = ((canvas_y - frame.y_offset) * frame.width + canvas_x - frame.x_offset)
+                    as usize;
+                let dst = (canvas_y * self.width + canvas_x) as usize;
+                let index = pixels[src];
+                self.data[dst] = match (transparent(index), frame.blend_op) {
+                    (None, _) => index,
+                    (Some(_), BlendOp::Over) => continue,
+                    (Some(transparent), BlendOp::Source) => transparent as u8,
+                };
+            }
+        }
+
+        let output = self.data.clone();
+
+        match frame.dispose_op {
+            DisposeOp::None => {}
+            DisposeOp::Background => self.clear(frame),
+            DisposeOp::Previous => match previous {
+                Some(previous) => self.data = previous,
+                None => self.clear(frame),
+            },
+        }
+        self.first = false;
+
+        output
+    }
+
+    /// 将帧所在的区域清空为透明索引
+    fn clear(&mut self, frame: &Frame) {
+        let right = (frame.x_offset + frame.width).min(self.width) as usize;
+        let bottom = (frame.y_offset + frame.height).min(self.height) as usize;
+        for y in frame.y_offset as usize..bottom {
+            let start = y * self.width as usize + frame.x_offset as usize;
+            let end = y * self.width as usize + right;
+            if start < end {
+                self.data[start..end].fill(self.background);
+            }
+        }
+    }
+}
+
+/// 将apng的`delay_num/delay_den`秒转换为gif的百分之一秒，`delay_den`为0时按100处理
+fn delay_centiseconds(delay_num: u16, delay_den: u16) -> u16 {
+    let delay_den = if delay_den == 0 {
+        100
+    } else {
+        delay_den as u32
+    };
+    let centiseconds = (delay_num as u32 * 100 + delay_den / 2) / delay_den;
+    centiseconds.min(u16::MAX as u32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u32, x_offset: u32, blend_op: BlendOp, pixels: Vec<u8>) -> Frame {
+        let mut frame = Frame::new(
+            Vec::new(),
+            width,
+            1,
+            x_offset,
+            0,
+            1,
+            10,
+            DisposeOp::None,
+            blend_op,
+        );
+        frame.pixels = Some(pixels);
+        frame
+    }
+
+    const PALETTE: [imagequant::RGBA; 3] = [
+        imagequant::RGBA::new(255, 0, 0, 255),
+        imagequant::RGBA::new(0, 0, 0, 0),
+        imagequant::RGBA::new(0, 0, 255, 255),
+    ];
+
+    fn read_gif(path: &Path) -> Vec<gif::Frame<'static>> {
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(path).unwrap())
+            .unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push(frame.clone());
+        }
+        frames
+    }
+
+    #[test]
+    fn source_blend_clears_canvas() {
+        let path = std::env::temp_dir().join("png-palettes-optimization-source-blend.gif");
+        let frames = [
+            frame(2, 0, BlendOp::Source, vec![0, 0]),
+            frame(1, 0, BlendOp::Source, vec![1]),
+            frame(1, 1, BlendOp::Over, vec![1]),
+        ];
+        write_gif(&path, 2, 1, &PALETTE, &frames, 0, 128).unwrap();
+        let gif_frames = read_gif(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let buffers: Vec<&[u8]> = gif_frames.iter().map(|frame| &frame.buffer[..]).collect();
+        // Source的透明像素清除画布，Over的透明像素保留画布
+        assert_eq!(buffers, [&[0, 0][..], &[1, 0], &[1, 0]]);
+        for frame in gif_frames.iter() {
+            assert_eq!((frame.width, frame.height), (2, 1));
+            assert_eq!(frame.dispose, DisposalMethod::Background);
+            assert_eq!(frame.transparent, Some(1));
+        }
+    }
+
+    #[test]
+    fn background_dispose_restores_transparent() {
+        let path = std::env::temp_dir().join("png-palettes-optimization-dispose.gif");
+        let mut first = frame(1, 1, BlendOp::Source, vec![2]);
+        first.dispose_op = DisposeOp::Background;
+        let frames = [first, frame(1, 0, BlendOp::Over, vec![0])];
+        write_gif(&path, 2, 1, &PALETTE, &frames, 1, 128).unwrap();
+        let gif_frames = read_gif(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let buffers: Vec<&[u8]> = gif_frames.iter().map(|frame| &frame.buffer[..]).collect();
+        assert_eq!(buffers, [&[1, 2][..], &[0, 1]]);
+    }
+
+    #[test]
+    fn canvas_too_large() {
+        let path = std::env::temp_dir().join("png-palettes-optimization-too-large.gif");
+        let err = write_gif(&path, 65536, 1, &PALETTE, &[], 0, 128).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,193 @@
+use super::Frame;
+use png::{BitDepth, ColorType, Compression, Decoder, Transformations};
+use std::{borrow::Cow, fs::File, io, io::BufWriter, path::Path};
+
+use crate::error::Error;
+
+/// 将解码、调色板生成等错误转换为`io::ErrorKind::InvalidData`错误
+pub fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// 将rgba数据写入为png图像
+pub fn write_rgba_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+    data: &[u8],
+    compression: Compression,
+) -> io::Result<()> {
+    let file = File::create(path)?;
+    let w = &mut BufWriter::new(file);
+
+    let mut encoder = png::Encoder::new(w, width, height);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(compression);
+    encoder.set_color(ColorType::Rgba);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+    Ok(())
+}
+
+/// 读取png图像并统一转换为8位rgba数据，返回宽、高和rgba数据
+pub fn read_rgba_png(path: &Path) -> io::Result<(u32, u32, Vec<u8>)> {
+    let mut decoder = Decoder::new(File::open(path)?);
+    // 展开调色板、低位深以及tRNS，并将16位转为8位
+    decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let output_info = reader.next_frame(&mut buf)?;
+    buf.truncate(output_info.buffer_size());
+
+    let data = expand_rgba(output_info.color_type, buf)?;
+
+    Ok((output_info.width, output_info.height, data))
+}
+
+/// 读取apng的所有帧并统一转换为8位rgba数据，返回宽、高和帧数据
+pub fn read_rgba_frames(path: &Path) -> io::Result<(u32, u32, Vec<Frame>)> {
+    let mut decoder = Decoder::new(File::open(path)?);
+    decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let (color_type, _) = reader.output_color_type();
+    let (width, height) = (reader.info().width, reader.info().height);
+
+    let mut frames = Frame::read_frames(&mut reader)?;
+    for frame in frames.iter_mut() {
+        frame.data = expand_rgba(color_type, std::mem::take(&mut frame.data))?;
+    }
+    Ok((width, height, frames))
+}
+
+/// 将`EXPAND`和`STRIP_16`转换后的8位图像数据统一转为rgba数据
+pub fn expand_rgba(color_type: ColorType, buf: Vec<u8>) -> io::Result<Vec<u8>> {
+    Ok(match color_type {
+        ColorType::Rgba => buf,
+        ColorType::Rgb => buf
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        ColorType::Grayscale => buf.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+        ColorType::Indexed => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                Error::UnsupportedColorMode,
+            ))
+        }
+    })
+}
+
+/// 将调色板和索引数据写入为调色板模式的png图像
+pub fn write_indexed_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+    palette: &[imagequant::RGBA],
+    pixels: &[u8],
+    compression: Compression,
+) -> io::Result<()> {
+    // 调色板数据格式转换为png规范
+    let mut rbg_palette: Vec<u8> = Vec::with_capacity(palette.len() * 3);
+    let mut trns: Vec<u8> = Vec::with_capacity(palette.len());
+    for f in palette.iter() {
+        rbg_palette.extend_from_slice(&[f.r, f.g, f.b]);
+        trns.push(f.a);
+    }
+
+    let file = File::create(path)?;
+    let w = &mut BufWriter::new(file);
+
+    let bit_depth = indexed_bit_depth(palette.len());
+    let mut encoder = png::Encoder::new(w, width, height);
+    encoder.set_depth(bit_depth);
+    encoder.set_compression(compression);
+    encoder.set_color(ColorType::Indexed);
+    encoder.set_trns(trns);
+    encoder.set_palette(rbg_palette);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pack_indices(pixels, width, bit_depth))?;
+    Ok(())
+}
+
+/// 根据调色板颜色数量选择调色板模式的位深，颜色越少每个像素占用的位数越少
+pub fn indexed_bit_depth(colors: usize) -> BitDepth {
+    match colors {
+        0..=2 => BitDepth::One,
+        3..=4 => BitDepth::Two,
+        5..=16 => BitDepth::Four,
+        _ => BitDepth::Eight,
+    }
+}
+
+/// 将每像素一个字节的索引数据按位深打包，每行不足一个字节的部分补0
+///
+/// 8位深时不需要打包，直接借用索引数据，避免复制整个图像
+pub fn pack_indices(pixels: &[u8], width: u32, bit_depth: BitDepth) -> Cow<'_, [u8]> {
+    let bits = bit_depth as usize;
+    if bits == 8 {
+        return Cow::Borrowed(pixels);
+    }
+    let per_byte = 8 / bits;
+    let row_bytes = (width as usize).div_ceil(per_byte);
+    let mut packed = Vec::with_capacity(pixels.len() / per_byte + row_bytes);
+    for row in pixels.chunks(width as usize) {
+        for chunk in row.chunks(per_byte) {
+            let mut byte = 0;
+            for (index, &pixel) in chunk.iter().enumerate() {
+                byte |= pixel << (8 - bits * (index + 1));
+            }
+            packed.push(byte);
+        }
+    }
+    Cow::Owned(packed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_eight_bits_borrows() {
+        let pixels = [1, 2, 3, 255];
+        let packed = pack_indices(&pixels, 2, BitDepth::Eight);
+        assert!(matches!(packed, Cow::Borrowed(_)));
+        assert_eq!(&packed[..], &pixels);
+    }
+
+    #[test]
+    fn pack_low_bit_depth_pads_rows() {
+        // 每行3个像素，每行单独补齐到字节
+        let pixels = [1, 0, 1, 0, 1, 1];
+        assert_eq!(
+            &pack_indices(&pixels, 3, BitDepth::One)[..],
+            &[0b1010_0000, 0b0110_0000]
+        );
+        let pixels = [3, 2, 1, 0, 1];
+        assert_eq!(
+            &pack_indices(&pixels, 5, BitDepth::Two)[..],
+            &[0b1110_0100, 0b0100_0000]
+        );
+        let pixels = [15, 1, 2, 3];
+        assert_eq!(
+            &pack_indices(&pixels, 1, BitDepth::Four)[..],
+            &[0xf0, 0x10, 0x20, 0x30]
+        );
+    }
+
+    #[test]
+    fn bit_depth_for_colors() {
+        assert_eq!(indexed_bit_depth(2), BitDepth::One);
+        assert_eq!(indexed_bit_depth(4), BitDepth::Two);
+        assert_eq!(indexed_bit_depth(16), BitDepth::Four);
+        assert_eq!(indexed_bit_depth(17), BitDepth::Eight);
+    }
+}
@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// 日志文件名，保存在工作路径下
+pub const JOURNAL_FILE_NAME: &str = ".png-palettes-optimization.journal";
+
+/// 日志中的一条记录，每行一个json对象
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    /// 相对于工作路径的文件路径
+    pub path: String,
+    /// 优化前文件的crc32
+    pub hash_before: String,
+    /// 优化后文件的crc32
+    pub hash_after: String,
+    /// 源文件大小
+    pub original_size: u64,
+    /// 压缩文件大小
+    pub size: u64,
+}
+
+/// 记录已经完成的工作任务，中断后使用`--resume`跳过已经完成的文件
+///
+/// 所有文件都完成后删除日志，只有中断或者有失败的文件时才保留日志
+#[derive(Debug)]
+pub struct Journal {
+    /// 日志文件路径
+    path: PathBuf,
+    /// 以追加方式打开的日志文件
+    file: File,
+    /// 上次运行完成的文件，键为相对于工作路径的文件路径
+    entries: HashMap<String, Entry>,
+}
+
+impl Journal {
+    /// 打开工作路径下的日志文件并继续追加，`resume`时读取已经完成的记录
+    ///
+    /// 不使用`resume`时也不清空日志，之前的记录在之后使用`resume`时仍然有效，
+    /// 记录的文件再次优化后追加的新记录覆盖之前的记录
+    pub fn open(dir: &Path, resume: bool) -> io::Result<Journal> {
+        let path = dir.join(JOURNAL_FILE_NAME);
+        let mut entries = HashMap::new();
+        if resume {
+            if let Ok(file) = File::open(&path) {
+                // 中断时最后一行可能没有写完整，无法解析的行忽略
+                for line in BufReader::new(file).lines() {
+                    if let Ok(entry) = serde_json::from_str::<Entry>(&line?) {
+                        entries.insert(entry.path.clone(), entry);
+                    }
+                }
+            }
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // 没有写完整的最后一行补上换行，之后追加的记录不会接在这一行后面
+        if fs::read(&path)?.last().is_some_and(|&byte| byte != b'\n') {
+            file.write_all(b"\n")?;
+        }
+        Ok(Journal {
+            path,
+            file,
+            entries,
+        })
+    }
+
+    /// 所有文件都完成后删除日志，不在工作路径下留下隐藏文件
+    pub fn remove(self) -> io::Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path)
+    }
+
+    /// 上次运行已经完成并且之后没有改变的文件，返回对应的记录
+    pub fn completed(&self, relative: &str, path: &Path) -> Option<&Entry> {
+        let entry = self.entries.get(relative)?;
+        let unchanged = fs::metadata(path).is_ok_and(|metadata| metadata.len() == entry.size)
+            && hash_file(path).is_ok_and(|hash| hash == entry.hash_after);
+        unchanged.then_some(entry)
+    }
+
+    /// 追加一条完成的记录，立即写入文件，进程中断时已经完成的记录不会丢失
+    pub fn record(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()
+    }
+}
+
+/// 计算文件内容的crc32，返回16进制字符串
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let data = fs::read(path)?;
+    Ok(format!("{:08x}", crc32fast::hash(&data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 在临时文件夹中创建`name`文件夹，写入一个文件并返回记录
+    fn setup(name: &str) -> (std::path::PathBuf, Entry) {
+        let dir = std::env::temp_dir().join(format!("png-palettes-optimization-journal-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hero.png");
+        fs::write(&path, b"optimized").unwrap();
+        let entry = Entry {
+            path: "hero.png".to_string(),
+            hash_before: "00000000".to_string(),
+            hash_after: hash_file(&path).unwrap(),
+            original_size: 100,
+            size: 9,
+        };
+        (dir, entry)
+    }
+
+    #[test]
+    fn resume_round_trip() {
+        let (dir, entry) = setup("round-trip");
+        let mut journal = Journal::open(&dir, false).unwrap();
+        journal.record(&entry).unwrap();
+        drop(journal);
+
+        let path = dir.join("hero.png");
+        let journal = Journal::open(&dir, true).unwrap();
+        let completed = journal.completed("hero.png", &path).unwrap();
+        assert_eq!(completed.original_size, 100);
+        assert_eq!(completed.size, 9);
+        assert!(journal.completed("other.png", &path).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_file_is_not_completed() {
+        let (dir, entry) = setup("changed");
+        let mut journal = Journal::open(&dir, false).unwrap();
+        journal.record(&entry).unwrap();
+        drop(journal);
+
+        let path = dir.join("hero.png");
+        // 大小相同但内容不同，crc32不一致
+        fs::write(&path, b"OPTIMIZED").unwrap();
+        assert!(Journal::open(&dir, true)
+            .unwrap()
+            .completed("hero.png", &path)
+            .is_none());
+        // 大小不一致
+        fs::write(&path, b"optimized!").unwrap();
+        assert!(Journal::open(&dir, true)
+            .unwrap()
+            .completed("hero.png", &path)
+            .is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn partial_line_and_restart() {
+        let (dir, entry) = setup("partial");
+        let mut journal = Journal::open(&dir, false).unwrap();
+        journal.record(&entry).unwrap();
+        drop(journal);
+
+        // 中断时没有写完整的最后一行被忽略
+        let journal_path = dir.join(JOURNAL_FILE_NAME);
+        let mut data = fs::read(&journal_path).unwrap();
+        data.extend_from_slice(b"{\"path\":\"other.png\",\"hash_");
+        fs::write(&journal_path, data).unwrap();
+        let path = dir.join("hero.png");
+        let mut journal = Journal::open(&dir, true).unwrap();
+        assert!(journal.completed("hero.png", &path).is_some());
+        // 之后追加的记录从新的一行开始
+        let other = Entry {
+            path: "other.png".to_string(),
+            ..entry.clone()
+        };
+        journal.record(&other).unwrap();
+        drop(journal);
+        fs::write(dir.join("other.png"), b"optimized").unwrap();
+        let journal = Journal::open(&dir, true).unwrap();
+        assert!(journal
+            .completed("other.png", &dir.join("other.png"))
+            .is_some());
+        drop(journal);
+
+        // 不使用`--resume`时不读取记录，但也不清空日志
+        let journal = Journal::open(&dir, false).unwrap();
+        assert!(journal.completed("hero.png", &path).is_none());
+        drop(journal);
+        let journal = Journal::open(&dir, true).unwrap();
+        assert!(journal.completed("hero.png", &path).is_some());
+
+        // 全部完成后删除日志
+        journal.remove().unwrap();
+        assert!(!journal_path.exists());
+        assert!(Journal::open(&dir, true)
+            .unwrap()
+            .completed("hero.png", &path)
+            .is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
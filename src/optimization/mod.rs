@@ -1,7 +1,27 @@
+mod assemble;
+mod convert;
+mod diff;
+mod extract;
 mod frame;
+mod gif_writer;
+mod image;
+mod journal;
+#[allow(clippy::module_inception)]
 mod optimization;
+mod options;
+mod pack;
+mod palette;
 mod pngquant;
+mod scheduler;
+mod ssim;
 
+pub use assemble::{Assemble, Delay};
+pub use convert::Convert;
+pub use extract::{Extract, ExtractMode};
 pub use frame::Frame;
 pub use optimization::Optimization;
-pub use pngquant::Pngquant;
+pub use options::{ByteSize, Options, Timeout};
+pub use pack::Pack;
+pub use palette::{parse_color, read_palette, PaletteFormat};
+pub use pngquant::{Pngquant, DEF_QUALITY_MAX};
+pub use scheduler::Strategy;
@@ -1,64 +1,69 @@
-use super::Pngquant;
-use crate::thread::ThreadPool;
-use crate::{BYTES_INTEGER, SECOND_CONSTANT};
-use clap::builder::Str;
+use super::journal::{self, Entry, Journal};
+use super::pngquant::{
+    is_generated, is_importance_map, quality_report, Probe, Progress, ProgressSender,
+    DEF_QUALITY_MAX,
+};
+use super::scheduler::{self, Cost, Scheduler};
+use super::{Options, Pngquant};
+use crate::error::Error;
+use crate::interrupt;
+use crate::thread::{JobResult, ThreadPool};
+use crate::{
+    BYTES_INTEGER, EXIT_FAILURE, EXIT_INTERRUPTED, EXIT_NOTHING_TO_DO, EXIT_SUCCESS,
+    PROGRESS_CONSTANT, SECOND_CONSTANT,
+};
 use colored::*;
-use png::Compression;
 use std::ffi::OsStr;
 use std::fs::{self, DirEntry};
 use std::io::{self, Write};
 use std::ops::{Add, Div};
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
 use std::thread::available_parallelism;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// 达到的质量不超过最低质量加上这个值时，在报告中提示
+const QUALITY_MIN_MARGIN: u8 = 5;
+
+/// 分配文件大小预算时试编码使用的最大颜色数量
+const BUDGET_COLORS: [u32; 13] = [256, 192, 128, 96, 64, 48, 32, 24, 16, 12, 8, 4, 2];
+
+/// 分配文件大小预算时使用设置的最大颜色数量试编码的最高质量，只使用低于设置的最高质量的值
+const BUDGET_QUALITIES: [u8; 5] = [50, 40, 30, 20, 10];
 
 #[derive(Debug)]
 pub struct Optimization<'a> {
     /// 工作路径
     path: &'a Path,
-    /// `1-10`.
-    ///更快的速度生成的图像质量更低，用于实时生成图像。
-    ///默认值为 `4`。
-    speed: Option<u8>,
-    /// `0-100`，优化的最低质量，默认最低`0`，不能高于最大值
-    quality_min: Option<u8>,
-    /// `0-100`，优化的最大质量，默认最高`100`，不能低于最小值
-    quality_max: Option<u8>,
-    /// 设置为1.0可获得漂亮的平滑图像，默认 1.0
-    dithering_level: Option<f32>,
+    /// 优化参数
+    options: Options,
     /// 文件扩展名，用于检测png文件
     extension: &'a [&'a str],
     /// 扫描到的png文件路径都保存到这里
     worklist: Vec<Work>,
     /// 线程池
     thread_pool: ThreadPool,
-    /// 记录完成的工作任务
-    end_num: usize,
-    /// png编码压缩等级
-    compression: Compression,
+    /// 线程池中线程的数量，也是所有工作任务共享的线程总数
+    jobs: usize,
     /// 工作开始时间
     start_time: u128,
     /// 处理的文件数量
     process_file_num: usize,
     /// 扫描PNG时排除的文件
     exclude: Option<Vec<String>>,
+    /// 记录完成的工作任务的日志，无法打开日志文件时为`None`
+    journal: Option<Journal>,
 }
 
 impl<'a> Optimization<'a> {
-    pub fn new(
-        path: &'a Path,
-        speed: Option<u8>,
-        quality_min: Option<u8>,
-        quality_max: Option<u8>,
-        dithering_level: Option<f32>,
-        compression: Compression,
-        exclude: Option<Vec<String>>,
-    ) -> Optimization {
-        // 系统并行资源
-        let available_parallelism = available_parallelism().unwrap().get();
+    pub fn new(path: &'a Path, options: Options, exclude: Option<Vec<String>>) -> Optimization<'a> {
+        // 没有设置同时处理的文件数量时使用系统并行资源
+        let jobs = options
+            .jobs
+            .unwrap_or_else(|| available_parallelism().map_or(1, |n| n.get()));
         // 根据并行资源数量创建线程池
-        let thread_pool = ThreadPool::new(available_parallelism);
+        let thread_pool = ThreadPool::new(jobs);
 
         let start_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -67,18 +72,15 @@ impl<'a> Optimization<'a> {
 
         Optimization {
             path,
-            speed,
-            quality_min,
-            quality_max,
+            options,
             extension: &["png"],
             worklist: vec![],
             thread_pool,
-            end_num: 0,
-            dithering_level: Some(dithering_level.unwrap_or(1.0)),
-            compression,
+            jobs,
             start_time,
             process_file_num: 0,
             exclude,
+            journal: None,
         }
     }
 
@@ -113,20 +115,37 @@ impl<'a> Optimization<'a> {
                 progress: 0,
                 original_size: 0,
                 size: 0,
+                quality: None,
+                mse: None,
+                ssim: None,
+                colors: None,
+                quality_max: None,
+                cost: Cost::default(),
+                threads: 0,
             })
         }
     }
 
     /// 检查文件扩展名以及需要排除的文件
     fn has_extension(&self, path: &Path) -> bool {
+        // 重要性图只用于辅助生成调色板，不需要优化
+        if is_importance_map(path) {
+            return false;
+        }
+
+        // 导出的调色板和误差图不需要优化
+        if is_generated(path) {
+            return false;
+        }
+
         if let Some(exclude) = &self.exclude {
-            let file_name = path.file_name().unwrap().to_str().unwrap();
+            let file_name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
             if exclude.iter().any(|f| f == file_name) {
                 return false;
             };
         }
 
-        if let Some(ref extension) = path.extension().and_then(OsStr::to_str) {
+        if let Some(extension) = path.extension().and_then(OsStr::to_str) {
             return self
                 .extension
                 .iter()
@@ -137,146 +156,273 @@ impl<'a> Optimization<'a> {
     }
 
     /// 生成工作列表
-    fn generate_worklist(&mut self) {
+    fn generate_worklist(&mut self) -> io::Result<()> {
         // 输出工作路径
-        println!("work Path: {}", self.path.to_str().unwrap().green());
+        println!("work Path: {}", self.path.display().to_string().green());
         let mut paths: Vec<Work> = vec![];
-        self.visit_dirs(self.path, &mut |entry| self.iterate_pngs(entry, &mut paths))
-            .unwrap();
+        self.visit_dirs(self.path, &mut |entry| self.iterate_pngs(entry, &mut paths))?;
         self.worklist = paths;
+        Ok(())
     }
 
     /// 执行数组中的工作任务
     fn run_worklist(&mut self) {
-        let (progress_sender, progress_receiver) = mpsc::sync_channel(self.worklist.len());
-        let (status_sender, status_receiver) = mpsc::channel::<Status>();
+        // 工作线程的进度和结果都发送到同一个事件通道
+        let (event_sender, event_receiver) = mpsc::channel::<Event>();
 
         let progress_total = (self.worklist.len() * 110) as f64;
         let pbstr = "\u{25A0}".repeat(20).to_string();
         let pbwid = "-".repeat(20).to_string();
 
-        // 主线程循环不断检查工作任务状态
-        loop {
-            for work in self.worklist.iter_mut() {
-                // 只执行初始化的工作任务
-                if let WorkStatus::INIT = work.status {
-                    // 开始执行，工作任务状态改为等待
-                    work.status = WorkStatus::WAIT;
-                    let path = work.path.path();
-                    let speed = self.speed;
-                    let quality_max = self.quality_max;
-                    let quality_min = self.quality_min;
-                    let dithering_level = self.dithering_level;
-                    let compression = self.compression;
-                    let progress_sender = progress_sender.clone();
-                    let status_sender = status_sender.clone();
-                    let id = work.id;
-                    // 多线程执行工作任务
-                    self.thread_pool.execute(move || {
-                        if let Ok(pngquant) = Pngquant::new(
-                            id,
-                            &path,
-                            speed,
-                            quality_min,
-                            quality_max,
-                            dithering_level,
-                            progress_sender,
-                        )
-                        .as_mut()
-                        {
-                            // 执行编码覆盖原文件
-                            pngquant.encoder(
-                                pngquant.path,
-                                speed,
-                                quality_min,
-                                quality_max,
-                                compression,
-                            );
-                            let original_size = pngquant.original_size.unwrap();
-                            let size = pngquant.size.unwrap();
-                            // 向主线程发送当前工作结束消息
-                            status_sender
-                                .send(Status {
-                                    id,
-                                    status: WorkStatus::End,
-                                    original_size,
-                                    size,
-                                })
-                                .unwrap();
-                        } else {
-                            status_sender
-                                .send(Status {
-                                    id,
-                                    status: WorkStatus::UNHANDLED,
-                                    original_size: 0,
-                                    size: 0,
-                                })
-                                .unwrap();
-                        }
-                    })
-                }
-            }
+        // 没有设置内存预算时使用系统可用内存
+        let max_memory = self.options.max_memory.or_else(scheduler::available_memory);
+        let mut scheduler = Scheduler::new(self.jobs, max_memory);
+        // 按调度策略决定开始执行工作任务的顺序
+        let costs: Vec<Cost> = self.worklist.iter().map(|work| work.cost).collect();
+        let mut queue: Vec<usize> = self
+            .options
+            .schedule
+            .order(&costs)
+            .into_iter()
+            .filter(|&index| matches!(self.worklist[index].status, WorkStatus::INIT))
+            .collect();
+        self.dispatch(&mut queue, &costs, &mut scheduler, &event_sender);
 
-            // 检查通道消息，执行工作的线程任务结束后将发消息到此通道
-            if let Ok(message) = status_receiver.try_recv() {
-                // 确定是哪个工作任务发出的消息
-                let work = self.worklist.iter_mut().find(|work| work.id == message.id);
-                if let Some(work) = work {
-                    match message.status {
-                        WorkStatus::End => {
-                            // 将工作任务状态改为已结束
-                            work.status = WorkStatus::End;
-                            work.original_size = message.original_size;
-                            work.size = message.size;
-                            self.process_file_num += 1;
-                        }
-                        WorkStatus::UNHANDLED => {
-                            // 将工作任务状态改为已结束
-                            work.status = WorkStatus::UNHANDLED;
-                        }
-                        _ => {}
-                    }
-                    self.end_num += 1;
-                }
+        // 阻塞等待工作线程发送的事件，直到没有正在执行的工作任务，
+        // 收到中断信号后不再开始新的工作任务，等待正在执行的工作任务中止或完成
+        while !scheduler.is_idle() {
+            let Ok(event) = event_receiver.recv() else {
+                break;
             };
-
-            if let Ok(progress) = progress_receiver.try_recv() {
-                let work = self.worklist.iter_mut().find(|work| work.id == progress.id);
-                if let Some(work) = work {
-                    // 改变工作进度
-                    work.progress = progress.value.round() as usize;
+            match event {
+                Event::Progress(Progress {
+                    warning: Some(warning),
+                    ..
+                }) => {
+                    // 工作线程的提示单独一行输出，之后重新显示进度条
+                    println!("\r{}", warning);
                     self.update_progress_bar(progress_total, &pbstr, &pbwid);
                 }
+                Event::Progress(progress) => {
+                    let work = self.worklist.iter_mut().find(|work| work.id == progress.id);
+                    if let Some(work) = work {
+                        // 改变工作进度
+                        work.progress = progress.value.round() as usize;
+                        self.update_progress_bar(progress_total, &pbstr, &pbwid);
+                    }
+                }
+                Event::Done(id, result) => {
+                    if let Some(work) = self.worklist.iter().find(|work| work.id == id) {
+                        scheduler.release(work.cost, work.threads);
+                    }
+                    self.finish_work(id, result);
+                    // 释放的线程和内存用于执行等待中的工作任务
+                    self.dispatch(&mut queue, &costs, &mut scheduler, &event_sender);
+                }
             }
+        }
 
-            // 判断是否所有任务已完成
-            if self.worklist.len() == self.end_num {
-                self.update_progress_bar(progress_total, &pbstr, &pbwid);
-                print!("\n");
+        self.update_progress_bar(progress_total, &pbstr, &pbwid);
+        println!();
+        if interrupt::is_interrupted() {
+            println!("{}", "interrupted, partial summary".yellow());
+        }
 
-                println!(
-                    "process the file: {}",
-                    self.process_file_num.to_string().green()
-                );
+        println!(
+            "process the file: {}",
+            self.process_file_num.to_string().green()
+        );
+        let resumed_num = self
+            .worklist
+            .iter()
+            .filter(|work| matches!(work.status, WorkStatus::Resumed))
+            .count();
+        if resumed_num > 0 {
+            println!(
+                "resumed, skip the file: {}",
+                resumed_num.to_string().green()
+            );
+        }
 
-                self.size_change_line();
+        self.quality_lines();
 
-                self.total_time_line();
+        self.size_change_line();
 
-                println!("complete all work!");
-                // 退出循环
-                break;
+        self.total_time_line();
+
+        self.failure_lines();
+    }
+
+    /// 按`queue`中工作列表的下标顺序开始执行等待中的工作任务，直到调度器没有空闲的线程，
+    /// 或者剩下的工作任务都超出内存预算
+    fn dispatch(
+        &mut self,
+        queue: &mut Vec<usize>,
+        costs: &[Cost],
+        scheduler: &mut Scheduler,
+        event_sender: &Sender<Event>,
+    ) {
+        if interrupt::is_interrupted() {
+            return;
+        }
+        while let Some((index, threads)) = scheduler.next(queue, costs) {
+            let work = &mut self.worklist[index];
+            // 开始执行，工作任务状态改为等待
+            work.status = WorkStatus::WAIT;
+            work.threads = threads;
+            let path = work.path.path();
+            let mut options = self.options.clone();
+            // 对比图按文件相对于工作路径的文件夹输出，避免同名文件覆盖
+            if let Some(diff_dir) = &options.diff_dir {
+                let relative = path
+                    .parent()
+                    .and_then(|parent| parent.strip_prefix(self.path).ok())
+                    .unwrap_or(Path::new(""));
+                options.diff_dir = Some(diff_dir.join(relative));
+            }
+            // 使用分配文件大小预算时选择的最大颜色数量和最高质量
+            if let Some(colors) = work.colors {
+                options.colors = Some(colors);
+            }
+            if let Some(quality_max) = work.quality_max {
+                options.quality_max = Some(quality_max);
+            }
+            let progress = event_sender.clone();
+            let progress_sender: ProgressSender = Arc::new(move |value| {
+                let _ = progress.send(Event::Progress(value));
+            });
+            let result_sender = event_sender.clone();
+            let id = work.id;
+            // 多线程执行工作任务，分配到多个线程的工作任务在同样大小的rayon线程池中执行
+            self.thread_pool.execute(
+                move || {
+                    // 从开始处理文件时计算超时
+                    options.deadline = options
+                        .timeout_per_file
+                        .map(|timeout| Instant::now() + timeout);
+                    scheduler::install(threads, || {
+                        Optimization::optimize_file(id, &path, &options, progress_sender)
+                    })
+                },
+                // 向主线程发送当前工作结束消息，线程panic时同样发送，避免主线程一直等待
+                move |result| {
+                    let _ = result_sender.send(Event::Done(id, result));
+                },
+            );
+        }
+    }
+
+    /// 在工作线程中优化单个文件，覆盖原文件并返回工作任务的结果
+    fn optimize_file(
+        id: usize,
+        path: &Path,
+        options: &Options,
+        progress_sender: ProgressSender,
+    ) -> Status {
+        match Pngquant::new(id, path, options, progress_sender) {
+            Ok(mut pngquant) => {
+                // 写入日志的优化前后文件的crc32
+                let hash_before = journal::hash_file(path).ok();
+                // 执行编码覆盖原文件
+                let status = match pngquant.encoder(path) {
+                    Ok(()) => WorkStatus::End,
+                    Err(Error::Interrupted) => WorkStatus::Interrupted,
+                    Err(err) => WorkStatus::Failed(err),
+                };
+                let original_size = pngquant.original_size.unwrap_or(0);
+                // 失败时原文件没有改变
+                let size = pngquant.size.unwrap_or(original_size);
+                let hash_after = match status {
+                    WorkStatus::End => journal::hash_file(path).ok(),
+                    _ => None,
+                };
+                Status {
+                    status,
+                    original_size,
+                    size,
+                    quality: pngquant.quality,
+                    mse: pngquant.mse,
+                    ssim: pngquant.ssim,
+                    hashes: hash_before.zip(hash_after),
+                }
+            }
+            // 不支持的颜色模式不处理
+            Err(err @ Error::UnsupportedColorMode) => {
+                Status::unchanged(WorkStatus::UNHANDLED(err), 0)
+            }
+            // apng生成共享调色板时收到中断信号
+            Err(Error::Interrupted) => {
+                let original_size = fs::metadata(path).map_or(0, |m| m.len());
+                Status::unchanged(WorkStatus::Interrupted, original_size)
+            }
+            // 无法读取或解码的文件处理失败，原文件保持不变
+            Err(err) => {
+                let original_size = fs::metadata(path).map_or(0, |m| m.len());
+                Status::unchanged(WorkStatus::Failed(err), original_size)
             }
         }
     }
 
+    /// 记录工作任务的结果，线程panic时记录为处理失败
+    fn finish_work(&mut self, id: usize, result: JobResult<Status>) {
+        // 确定是哪个工作任务发出的消息
+        let Some(work) = self.worklist.iter_mut().find(|work| work.id == id) else {
+            return;
+        };
+        let message = result.unwrap_or_else(|message| {
+            let original_size = work.path.metadata().map_or(0, |m| m.len());
+            Status::unchanged(
+                WorkStatus::Failed(Error::WorkerPanicked(message)),
+                original_size,
+            )
+        });
+        match message.status {
+            WorkStatus::End => {
+                // 将工作任务状态改为已结束
+                work.status = WorkStatus::End;
+                work.original_size = message.original_size;
+                work.size = message.size;
+                work.quality = message.quality;
+                work.mse = message.mse;
+                work.ssim = message.ssim;
+                self.process_file_num += 1;
+                // 日志写入失败不影响优化结果，只是中断后无法跳过这个文件
+                if let (Some(journal), Some((hash_before, hash_after))) =
+                    (self.journal.as_mut(), message.hashes)
+                {
+                    let _ = journal.record(&Entry {
+                        path: relative_path(self.path, &work.path.path()),
+                        hash_before,
+                        hash_after,
+                        original_size: work.original_size,
+                        size: work.size,
+                    });
+                }
+            }
+            WorkStatus::UNHANDLED(err) => {
+                // 将工作任务状态改为未处理
+                work.status = WorkStatus::UNHANDLED(err);
+            }
+            WorkStatus::Failed(err) => {
+                // 将工作任务状态改为失败，文件大小不变
+                work.status = WorkStatus::Failed(err);
+                work.original_size = message.original_size;
+                work.size = message.size;
+                work.ssim = message.ssim;
+            }
+            WorkStatus::Interrupted => {
+                // 将工作任务状态改为中断，文件大小不变
+                work.status = WorkStatus::Interrupted;
+                work.original_size = message.original_size;
+                work.size = message.size;
+            }
+            _ => {}
+        }
+    }
+
     /// 更新进度条
-    fn update_progress_bar(&self, progress_total: f64, pbstr: &String, pbwid: &String) {
-        let current_value = self
-            .worklist
-            .iter()
-            .map(move |f| f.progress)
-            .fold(0, |acc, x| acc + x) as f64;
+    fn update_progress_bar(&self, progress_total: f64, pbstr: &str, pbwid: &str) {
+        let current_value = self.worklist.iter().map(move |f| f.progress).sum::<usize>() as f64;
         let perc = current_value / progress_total;
         let lpad = (perc * 20.00).floor();
         let pbstr = &pbstr[0..'\u{25A0}'.len_utf8() * (lpad.trunc() as usize)].green();
@@ -286,6 +432,117 @@ impl<'a> Optimization<'a> {
         io::stdout().flush().unwrap();
     }
 
+    /// 输出每个文件达到的质量，质量接近最低质量的文件用黄色提示
+    fn quality_lines(&self) {
+        let quality_min = self.options.quality_min.unwrap_or(0);
+        let mut qualities = vec![];
+        let mut low_quality_num = 0;
+        for work in self.worklist.iter() {
+            if let WorkStatus::End = work.status {
+                let report = quality_report(work.quality, work.mse, work.ssim);
+                let path = work.path.path().display().to_string();
+                match work.quality {
+                    Some(quality) if quality <= quality_min.saturating_add(QUALITY_MIN_MARGIN) => {
+                        low_quality_num += 1;
+                        println!(
+                            "{} {} {}",
+                            path.yellow(),
+                            report.yellow(),
+                            "near quality_min".yellow()
+                        );
+                    }
+                    _ => println!("{} {}", path, report.green()),
+                }
+                qualities.extend(work.quality);
+            }
+        }
+
+        if let Some(lowest) = qualities.iter().min() {
+            let average = qualities.iter().map(|&quality| quality as f64).sum::<f64>()
+                / qualities.len() as f64;
+            println!(
+                "average quality: {} lowest quality: {}",
+                format!("{:.1}", average).green(),
+                lowest.to_string().green()
+            );
+        }
+        if low_quality_num > 0 {
+            println!("near quality_min: {}", low_quality_num.to_string().yellow());
+        }
+    }
+
+    /// 最后输出未处理和处理失败的文件以及原因，没有时输出全部完成
+    fn failure_lines(&self) {
+        let mut unhandled_num = 0;
+        let mut failed_num = 0;
+        let mut aborted_num = 0;
+        let mut not_started_num = 0;
+        for work in self.worklist.iter() {
+            let path = work.path.path().display().to_string();
+            match &work.status {
+                WorkStatus::UNHANDLED(err) => {
+                    unhandled_num += 1;
+                    println!(
+                        "unhandled file: {} {}",
+                        path.yellow(),
+                        err.to_string().yellow()
+                    );
+                }
+                WorkStatus::Failed(err) => {
+                    failed_num += 1;
+                    let ssim = work
+                        .ssim
+                        .map(|ssim| format!(" SSIM: {:.4}", ssim))
+                        .unwrap_or_default();
+                    println!(
+                        "failed file: {} {}{}",
+                        path.red(),
+                        err.to_string().red(),
+                        ssim.red()
+                    );
+                }
+                WorkStatus::Interrupted => aborted_num += 1,
+                WorkStatus::INIT => not_started_num += 1,
+                _ => {}
+            }
+        }
+
+        // 中断时只输出中止和没有开始的文件数量，这些文件都保持原文件不变
+        if interrupt::is_interrupted() {
+            println!(
+                "interrupted: {} aborted, {} not started",
+                aborted_num.to_string().yellow(),
+                not_started_num.to_string().yellow()
+            );
+        }
+
+        if failed_num > 0 {
+            println!(
+                "unhandled: {} failed: {}",
+                unhandled_num.to_string().yellow(),
+                failed_num.to_string().red()
+            );
+        } else if interrupt::is_interrupted() {
+            if unhandled_num > 0 {
+                println!("unhandled: {}", unhandled_num.to_string().yellow());
+            }
+        } else if unhandled_num > 0 {
+            println!("unhandled: {}", unhandled_num.to_string().yellow());
+            println!("complete all work!");
+        } else {
+            println!("complete all work!");
+        }
+    }
+
+    /// 根据工作任务的结果返回进程退出码
+    fn exit_code(&self) -> u8 {
+        exit_code(
+            interrupt::is_interrupted(),
+            self.worklist.iter().map(|work| &work.status),
+            self.process_file_num,
+        )
+    }
+
     /// 输出文件大小变化
     fn size_change_line(&self) {
         // 压缩前总大小
@@ -293,15 +550,11 @@ impl<'a> Optimization<'a> {
             .worklist
             .iter()
             .map(move |f| f.original_size)
-            .fold(0, |acc, x| acc + x) as f64)
+            .sum::<u64>() as f64)
             / BYTES_INTEGER)
             .round();
         // 压缩后总大小
-        let total_size = ((self
-            .worklist
-            .iter()
-            .map(move |f| f.size)
-            .fold(0, |acc, x| acc + x) as f64)
+        let total_size = ((self.worklist.iter().map(move |f| f.size).sum::<u64>() as f64)
             / BYTES_INTEGER)
             .round();
         // 总减少大小
@@ -328,10 +581,216 @@ impl<'a> Optimization<'a> {
         println!("total time: {}", second.green());
     }
 
-    /// 优化图片
-    pub fn quality(&mut self) {
-        self.generate_worklist();
+    /// 所有png图像生成一个共享调色板，之后每个文件都重新映射到这个调色板
+    fn generate_shared_palette(&mut self) {
+        println!("generate shared palette");
+        // 输出调色板生成的进度
+        let (progress_sender, progress) = Progress::printer();
+        let palette = Pngquant::attributes(0, &self.options, DEF_QUALITY_MAX, progress_sender)
+            .and_then(|attr| {
+                let mut histogram = Pngquant::histogram(&attr, &self.options)?;
+                for work in self.worklist.iter() {
+                    // 不支持或无法解码的文件跳过，执行工作任务时会报告
+                    let _ = Pngquant::add_to_histogram(&work.path.path(), &attr, &mut histogram);
+                }
+                histogram.quantize(&attr).map(|mut res| res.palette_vec())
+            });
+        progress.join().unwrap();
+
+        match palette {
+            Ok(palette) => {
+                println!(
+                    "shared palette colors: {}",
+                    palette.len().to_string().green()
+                );
+                self.options.palette = Some(palette);
+            }
+            Err(err) => println!("shared palette failed: {}", err.to_string().red()),
+        }
+    }
+
+    /// 在所有文件之间分配整个目录的文件大小预算
+    ///
+    /// 先用不同的最大颜色数量和最高质量试编码每个文件，得到编码后的大小和总误差，然后每次选择
+    /// 减少每个字节增加总误差最少的文件降低一档参数，直到总大小满足预算。
+    /// 总误差为均方误差乘以像素数量，大而复杂的图像降低参数能减少更多字节，所以会承担更多的压缩
+    fn allocate_budget(&mut self, total_bytes: u64) {
+        println!(
+            "allocate byte budget: {}",
+            format!("{}KB", (total_bytes as f64 / BYTES_INTEGER).round()).green()
+        );
+        // 设置的最大颜色数量和最高质量下的颜色数量，以及设置的最大颜色数量下的更低质量
+        let max_colors = self.options.colors.unwrap_or(256);
+        let quality_max = self.options.quality_max.unwrap_or(DEF_QUALITY_MAX);
+        let quality_min = self.options.quality_min.unwrap_or(0);
+        let settings: Vec<(u32, Option<u8>)> = BUDGET_COLORS
+            .into_iter()
+            .filter(|&colors| colors <= max_colors)
+            .map(|colors| (colors, None))
+            .chain(
+                BUDGET_QUALITIES
+                    .into_iter()
+                    .filter(|&quality| quality < quality_max && quality >= quality_min)
+                    .map(|quality| (max_colors, Some(quality))),
+            )
+            .collect();
+
+        // 试编码与优化文件一样由调度器分配线程和内存，并且使用单个文件的超时
+        let costs: Vec<Cost> = self.worklist.iter().map(|work| work.cost).collect();
+        // `--resume`跳过的文件已经完成，不再试编码，按记录的压缩后大小计入总大小
+        let mut queue: Vec<usize> = self
+            .options
+            .schedule
+            .order(&costs)
+            .into_iter()
+            .filter(|&index| !matches!(self.worklist[index].status, WorkStatus::Resumed))
+            .collect();
+        let probe_num = queue.len();
+        let max_memory = self.options.max_memory.or_else(scheduler::available_memory);
+        let mut scheduler = Scheduler::new(self.jobs, max_memory);
+        // 试编码的调色板生成进度不需要输出
+        let progress_sender: ProgressSender = Arc::new(|_| {});
+        let (result_sender, result_receiver) = mpsc::channel::<(usize, usize, Vec<Probe>)>();
+
+        // 每个文件按大小从大到小、总误差从小到大的试编码结果
+        let mut points: Vec<Vec<Probe>> = vec![vec![]; self.worklist.len()];
+        let mut probed = 0;
+        loop {
+            // 收到中断信号后不再开始新的试编码
+            while !interrupt::is_interrupted() {
+                let Some((index, threads)) = scheduler.next(&mut queue, &costs) else {
+                    break;
+                };
+                let work = &self.worklist[index];
+                let path = work.path.path();
+                let mut options = self.options.clone();
+                let settings = settings.clone();
+                let progress_sender = progress_sender.clone();
+                let result_sender = result_sender.clone();
+                let id = work.id;
+                self.thread_pool.execute(
+                    move || {
+                        // 从开始试编码文件时计算超时
+                        options.deadline = options
+                            .timeout_per_file
+                            .map(|timeout| Instant::now() + timeout);
+                        scheduler::install(threads, || {
+                            match Pngquant::new(id, &path, &options, progress_sender).as_mut() {
+                                Ok(pngquant) => pngquant.probe(&settings),
+                                Err(_) => vec![],
+                            }
+                        })
+                    },
+                    // 无法试编码或者试编码panic时按不支持的文件处理，保持原大小
+                    move |result| {
+                        let _ = result_sender.send((index, threads, result.unwrap_or_default()));
+                    },
+                );
+            }
+            if scheduler.is_idle() {
+                break;
+            }
+            let Ok((index, threads, work_points)) = result_receiver.recv() else {
+                break;
+            };
+            scheduler.release(costs[index], threads);
+            points[index] = frontier(work_points);
+            probed += 1;
+            print!(
+                "\rprobe the file: {}",
+                format!("{}/{}", probed, probe_num).green()
+            );
+            io::stdout().flush().unwrap();
+        }
+        println!();
+
+        // 不支持的文件保持原大小，跳过的文件使用记录的压缩后大小
+        let fixed: u64 = self
+            .worklist
+            .iter()
+            .zip(points.iter())
+            .filter(|(work, points)| {
+                points.is_empty() || matches!(work.status, WorkStatus::Resumed)
+            })
+            .map(|(work, _)| match work.status {
+                WorkStatus::Resumed => work.size,
+                _ => work.path.metadata().map_or(0, |metadata| metadata.len()),
+            })
+            .sum();
+        let (levels, total) = allocate(&points, fixed, total_bytes);
+
+        for ((work, points), level) in self.worklist.iter_mut().zip(points.iter()).zip(levels) {
+            if let Some(point) = points.get(level) {
+                work.colors = Some(point.colors);
+                work.quality_max = point.quality_max;
+            }
+        }
+
+        let estimated = format!("{}KB", (total as f64 / BYTES_INTEGER).round());
+        if total > total_bytes {
+            println!(
+                "byte budget cannot be met, estimated total: {}",
+                estimated.red()
+            );
+        } else {
+            println!("estimated total: {}", estimated.green());
+        }
+    }
+
+    /// 打开工作路径下的日志，`--resume`时跳过上次运行已经完成并且之后没有改变的文件
+    fn open_journal(&mut self) {
+        let journal = match Journal::open(self.path, self.options.resume) {
+            Ok(journal) => journal,
+            Err(err) => {
+                println!("journal disabled: {}", err.to_string().yellow());
+                return;
+            }
+        };
+        for work in self.worklist.iter_mut() {
+            let path = work.path.path();
+            if let Some(entry) = journal.completed(&relative_path(self.path, &path), &path) {
+                work.status = WorkStatus::Resumed;
+                work.progress = PROGRESS_CONSTANT as usize;
+                work.original_size = entry.original_size;
+                work.size = entry.size;
+            }
+        }
+        self.journal = Some(journal);
+    }
+
+    /// 优化图片，返回进程退出码
+    pub fn quality(&mut self) -> u8 {
+        // 收到中断信号时停止开始新的工作任务并输出已完成部分的结果
+        interrupt::install();
+        if let Err(err) = self.generate_worklist() {
+            eprintln!("{}", err.to_string().red());
+            return EXIT_FAILURE;
+        }
+        if self.worklist.is_empty() {
+            println!("{}", Error::NoInputFiles.to_string().yellow());
+            return EXIT_NOTHING_TO_DO;
+        }
+        self.open_journal();
+        // 读取文件头估计每个工作任务的像素数量和内存，试编码和优化文件时都用于调度
+        for work in self.worklist.iter_mut() {
+            work.cost = Cost::estimate(&work.path.path());
+        }
+        // 已经设置了固定调色板时不再生成共享调色板
+        if self.options.shared_palette && self.options.palette.is_none() {
+            self.generate_shared_palette();
+        }
+        if let Some(total_bytes) = self.options.total_bytes {
+            self.allocate_budget(total_bytes);
+        }
         self.run_worklist();
+        let code = self.exit_code();
+        // 所有文件都完成时不再需要继续运行，删除日志；中断或者有失败的文件时保留日志用于`--resume`
+        if matches!(code, EXIT_SUCCESS | EXIT_NOTHING_TO_DO) {
+            if let Some(Err(err)) = self.journal.take().map(Journal::remove) {
+                println!("journal not removed: {}", err.to_string().yellow());
+            }
+        }
+        code
     }
 }
 
@@ -349,10 +808,25 @@ struct Work {
     pub original_size: u64,
     /// 压缩文件大小
     pub size: u64,
+    /// 量化达到的质量
+    quality: Option<u8>,
+    /// 量化的均方误差
+    mse: Option<f64>,
+    /// 写入的文件与源图像的结构相似度
+    ssim: Option<f64>,
+    /// 分配文件大小预算时选择的最大颜色数量
+    colors: Option<u32>,
+    /// 分配文件大小预算时选择的最高质量
+    quality_max: Option<u8>,
+    /// 估计的像素数量和内存
+    cost: Cost,
+    /// 执行时分配的线程数量
+    threads: usize,
 }
 
 /// 工作任务状态
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum WorkStatus {
     /// 初始化
     INIT,
@@ -361,17 +835,203 @@ pub enum WorkStatus {
     /// 正在执行
     WAIT,
     /// 未处理，不支持的png格式
-    UNHANDLED,
+    UNHANDLED(Error),
+    /// 处理失败，原文件保持不变
+    Failed(Error),
+    /// 收到中断信号时中止，原文件保持不变
+    Interrupted,
+    /// 上次运行已经完成，`--resume`时跳过
+    Resumed,
+}
+
+/// 工作线程发送给主线程的事件
+enum Event {
+    /// 调色板生成和编码的进度
+    Progress(Progress),
+    /// 工作任务结束，线程panic时为panic的信息
+    Done(usize, JobResult<Status>),
 }
 
 #[derive(Debug)]
 pub struct Status {
-    /// 工作id
-    pub id: usize,
     /// 工作任务状态
     pub status: WorkStatus,
     /// 源文件大小
     pub original_size: u64,
     /// 压缩文件大小
     pub size: u64,
+    /// 量化达到的质量
+    pub quality: Option<u8>,
+    /// 量化的均方误差
+    pub mse: Option<f64>,
+    /// 写入的文件与源图像的结构相似度
+    pub ssim: Option<f64>,
+    /// 优化前后文件的crc32，用于写入日志
+    pub hashes: Option<(String, String)>,
+}
+
+impl Status {
+    /// 没有写入文件的工作任务结果，文件大小不变
+    fn unchanged(status: WorkStatus, original_size: u64) -> Status {
+        Status {
+            status,
+            original_size,
+            size: original_size,
+            quality: None,
+            mse: None,
+            ssim: None,
+            hashes: None,
+        }
+    }
+}
+
+/// 根据是否收到中断信号、工作任务的状态和处理的文件数量返回进程退出码
+fn exit_code<'a>(
+    interrupted: bool,
+    statuses: impl IntoIterator<Item = &'a WorkStatus>,
+    process_file_num: usize,
+) -> u8 {
+    if interrupted {
+        return EXIT_INTERRUPTED;
+    }
+    let failed = statuses
+        .into_iter()
+        .any(|status| matches!(status, WorkStatus::Failed(_)));
+    if failed {
+        EXIT_FAILURE
+    } else if process_file_num == 0 {
+        EXIT_NOTHING_TO_DO
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+/// 只保留试编码结果中有效的参数，返回按大小从大到小、总误差从小到大排列的结果
+///
+/// 另一组参数编码后更小并且总误差不更大时，这组参数不会被选择
+fn frontier(mut points: Vec<Probe>) -> Vec<Probe> {
+    points.sort_by(|a, b| a.size.cmp(&b.size).then(a.error.total_cmp(&b.error)));
+    let mut frontier: Vec<Probe> = Vec::with_capacity(points.len());
+    for point in points {
+        if frontier.last().is_none_or(|last| point.error < last.error) {
+            frontier.push(point);
+        }
+    }
+    frontier.reverse();
+    frontier
+}
+
+/// 按试编码结果分配文件大小预算，返回每个文件选择的试编码结果的下标和估计的总大小
+///
+/// `points`为每个文件经过`frontier`的试编码结果，`fixed`为不参与分配的文件的总大小。
+/// 每次选择降低一档参数时减少每个字节增加总误差最少的文件，直到总大小满足预算或者都无法再降低
+fn allocate(points: &[Vec<Probe>], fixed: u64, total_bytes: u64) -> (Vec<usize>, u64) {
+    let mut levels = vec![0; points.len()];
+    let mut total = fixed
+        + points
+            .iter()
+            .filter_map(|points| points.first())
+            .map(|point| point.size)
+            .sum::<u64>();
+    while total > total_bytes {
+        let next = points
+            .iter()
+            .zip(levels.iter())
+            .enumerate()
+            .filter(|(_, (points, &level))| level + 1 < points.len())
+            .map(|(index, (points, &level))| {
+                let (point, next) = (points[level], points[level + 1]);
+                let cost = (next.error - point.error) / (point.size - next.size) as f64;
+                (index, cost)
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((index, _)) = next else {
+            break;
+        };
+        let level = levels[index];
+        total -= points[index][level].size - points[index][level + 1].size;
+        levels[index] += 1;
+    }
+    (levels, total)
+}
+
+/// 文件相对于工作路径的路径，用作日志中的键
+fn relative_path(dir: &Path, path: &Path) -> String {
+    path.strip_prefix(dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_code_success_and_nothing_to_do() {
+        let statuses = [
+            WorkStatus::End,
+            WorkStatus::UNHANDLED(Error::UnsupportedColorMode),
+        ];
+        assert_eq!(exit_code(false, &statuses, 1), EXIT_SUCCESS);
+        // 所有文件都不支持或者没有文件
+        assert_eq!(exit_code(false, &statuses[1..], 0), EXIT_NOTHING_TO_DO);
+        assert_eq!(exit_code(false, &[], 0), EXIT_NOTHING_TO_DO);
+    }
+
+    #[test]
+    fn exit_code_failure_and_interrupted() {
+        let statuses = [WorkStatus::End, WorkStatus::Failed(Error::OverBudget)];
+        assert_eq!(exit_code(false, &statuses, 1), EXIT_FAILURE);
+        // 中断优先于失败
+        assert_eq!(exit_code(true, &statuses, 1), EXIT_INTERRUPTED);
+        assert_eq!(exit_code(true, &[], 0), EXIT_INTERRUPTED);
+    }
+
+    fn probe(colors: u32, quality_max: Option<u8>, size: u64, error: f64) -> Probe {
+        Probe {
+            colors,
+            quality_max,
+            size,
+            error,
+        }
+    }
+
+    #[test]
+    fn frontier_drops_dominated() {
+        let points = frontier(vec![
+            probe(64, None, 600, 20.0),
+            probe(256, None, 1000, 10.0),
+            // 比64色更大但误差更高
+            probe(128, None, 800, 30.0),
+            probe(256, Some(40), 700, 15.0),
+            // 与64色一样大但误差更高
+            probe(48, None, 600, 25.0),
+        ]);
+        let sizes: Vec<u64> = points.iter().map(|point| point.size).collect();
+        assert_eq!(sizes, [1000, 700, 600]);
+        assert_eq!(points[1].quality_max, Some(40));
+        assert!(frontier(vec![]).is_empty());
+    }
+
+    #[test]
+    fn allocate_by_total_error() {
+        // 两个文件减少相同的字节，小图像增加的均方误差更多，但大图像的像素数量更多，总误差更多
+        let sprite = vec![
+            probe(256, None, 1000, 100.0 * 10.0),
+            probe(16, None, 500, 100.0 * 50.0),
+        ];
+        let atlas = vec![
+            probe(256, None, 1000, 10000.0 * 5.0),
+            probe(16, None, 500, 10000.0 * 20.0),
+        ];
+        let points = [sprite, atlas];
+        let (levels, total) = allocate(&points, 0, 1500);
+        assert_eq!(levels, [1, 0]);
+        assert_eq!(total, 1500);
+
+        // 预算足够时都使用误差最小的参数，无法满足时降低到最小
+        assert_eq!(allocate(&points, 0, 2000), (vec![0, 0], 2000));
+        assert_eq!(allocate(&points, 100, 500), (vec![1, 1], 1100));
+    }
 }
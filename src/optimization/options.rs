@@ -0,0 +1,171 @@
+use super::{PaletteFormat, Strategy};
+use crate::BYTES_INTEGER;
+use png::Compression;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// 优化参数，由命令行参数生成，每个工作任务复制一份
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// `1-10`.
+    ///更快的速度生成的图像质量更低，用于实时生成图像。
+    ///默认值为 `4`。
+    pub speed: Option<u8>,
+    /// `0-100`，优化的最低质量，默认最低`0`，不能高于最大值
+    pub quality_min: Option<u8>,
+    /// `0-100`，优化的最大质量，默认最高`100`，不能低于最小值
+    pub quality_max: Option<u8>,
+    /// 设置为1.0可获得漂亮的平滑图像，默认 1.0
+    pub dithering_level: Option<f32>,
+    /// `2-256`，调色板的最大颜色数量，不超过16个颜色时输出4位或更低位深的图像
+    pub colors: Option<u32>,
+    /// png编码压缩等级
+    pub compression: Compression,
+    /// apng同时导出同名gif动画，值为alpha阈值，低于阈值的像素在gif中完全透明
+    pub gif: Option<u8>,
+    /// 工作路径下所有图像使用同一个共享调色板
+    pub shared_palette: bool,
+    /// 固定调色板，设置后不再为每个文件生成调色板，而是重新映射到这个调色板
+    pub palette: Option<Vec<imagequant::RGBA>>,
+    /// 必须保留在调色板中的固定颜色，用于品牌色等不能偏移的颜色
+    pub fixed_colors: Vec<imagequant::RGBA>,
+    /// 单个文件编码后的最大字节数，设置后自动查找满足限制的最高质量
+    pub max_bytes: Option<u64>,
+    /// 所有文件编码后的总字节数，设置后按文件的大小和复杂度分配每个文件的质量
+    pub total_bytes: Option<u64>,
+    /// 写入的文件与源图像的最低结构相似度，低于阈值时恢复原文件
+    pub min_ssim: Option<f64>,
+    /// 误差热力图和对比图的输出文件夹
+    pub diff_dir: Option<PathBuf>,
+    /// 同时输出源图像和优化后图像的左右对比图
+    pub side_by_side: bool,
+    /// 同时导出优化后图像使用的调色板文件
+    pub export_palette: Option<PaletteFormat>,
+    /// 同时处理的文件数量，也是所有文件共享的线程总数，默认为系统的并行线程数
+    pub jobs: Option<usize>,
+    /// 同时处理的文件估计占用的最大内存字节数，默认为系统可用内存
+    pub max_memory: Option<u64>,
+    /// 读取工作路径下的日志，跳过上次运行已经完成的文件
+    pub resume: bool,
+    /// 单个文件的最长处理时间，超时时中止并保留原文件
+    pub timeout_per_file: Option<Duration>,
+    /// 单个文件的截止时间，开始处理文件时根据`timeout_per_file`设置
+    pub deadline: Option<Instant>,
+    /// 工作任务开始执行的顺序
+    pub schedule: Strategy,
+}
+
+impl Options {
+    /// 是否已经超过当前文件的截止时间
+    pub fn timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// 文件大小，支持`B`、`K`/`KB`、`M`/`MB`、`G`/`GB`单位，按1024换算，例如`50K`、`1.5M`
+#[derive(Clone, Copy, Debug)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim().to_ascii_uppercase();
+        let (number, unit) = value
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .map_or((&value[..], ""), |index| value.split_at(index));
+        let scale = match unit.trim() {
+            "" | "B" => 1.0,
+            "K" | "KB" => BYTES_INTEGER,
+            "M" | "MB" => BYTES_INTEGER * BYTES_INTEGER,
+            "G" | "GB" => BYTES_INTEGER * BYTES_INTEGER * BYTES_INTEGER,
+            _ => return Err(format!("invalid size `{}`", s)),
+        };
+        match number.parse::<f64>() {
+            Ok(number) if number > 0.0 => Ok(ByteSize((number * scale).round() as u64)),
+            _ => Err(format!("invalid size `{}`", s)),
+        }
+    }
+}
+
+/// 时长，支持`ms`、`s`、`m`单位，没有单位时为秒，例如`500ms`、`30`、`1.5m`
+#[derive(Clone, Copy, Debug)]
+pub struct Timeout(pub Duration);
+
+impl FromStr for Timeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim().to_ascii_lowercase();
+        let (number, unit) = value
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .map_or((&value[..], ""), |index| value.split_at(index));
+        let scale = match unit.trim() {
+            "ms" => 0.001,
+            "" | "s" => 1.0,
+            "m" => 60.0,
+            _ => return Err(format!("invalid timeout `{}`", s)),
+        };
+        match number.parse::<f64>() {
+            Ok(number) if number > 0.0 => Ok(Timeout(Duration::from_secs_f64(number * scale))),
+            _ => Err(format!("invalid timeout `{}`", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_byte_size_units() {
+        let size = |s: &str| s.parse::<ByteSize>().map(|size| size.0);
+        assert_eq!(size("512"), Ok(512));
+        assert_eq!(size("512b"), Ok(512));
+        assert_eq!(size("50K"), Ok(50 * 1024));
+        assert_eq!(size("50 kb"), Ok(50 * 1024));
+        assert_eq!(size("1.5M"), Ok(1536 * 1024));
+        assert_eq!(size("2GB"), Ok(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn parse_timeout_units() {
+        let timeout = |s: &str| s.parse::<Timeout>().map(|timeout| timeout.0);
+        assert_eq!(timeout("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(timeout("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(timeout("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(timeout("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(timeout(" 2 S "), Ok(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn parse_timeout_invalid() {
+        for s in ["", "0", "0ms", "s", "-1s", "1h", "abc"] {
+            assert!(s.parse::<Timeout>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn timed_out_after_deadline() {
+        assert!(!Options::default().timed_out());
+        let options = Options {
+            deadline: Some(Instant::now()),
+            ..Options::default()
+        };
+        assert!(options.timed_out());
+        let options = Options {
+            deadline: Some(Instant::now() + Duration::from_secs(60)),
+            ..Options::default()
+        };
+        assert!(!options.timed_out());
+    }
+
+    #[test]
+    fn parse_byte_size_invalid() {
+        for s in ["", "0", "K", "-1K", "1T", "1.2.3M", "abc"] {
+            assert!(s.parse::<ByteSize>().is_err(), "{}", s);
+        }
+    }
+}
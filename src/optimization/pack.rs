@@ -0,0 +1,457 @@
+use super::image::{invalid_data, read_rgba_png, write_indexed_png};
+use super::palette::write_palette;
+use super::pngquant::{is_generated, is_importance_map, Progress, DEF_QUALITY_MAX};
+use super::{Options, Pngquant};
+use crate::error::Error;
+use colored::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// 将文件夹中的小图打包为使用共享调色板的图集
+#[derive(Debug)]
+pub struct Pack<'a> {
+    /// 小图所在的文件夹
+    path: &'a Path,
+    /// 图集输出文件夹，默认为输入文件夹同级的`<文件夹名>_atlas`文件夹
+    output: PathBuf,
+    /// 图集文件名前缀，生成`<name>_0.png`和`<name>_0.json`
+    name: String,
+    /// 单张图集的最大宽高
+    max_size: u32,
+    /// 小图之间的间距
+    padding: u32,
+    /// 优化参数
+    options: Options,
+}
+
+/// 读取的小图
+struct Sprite {
+    /// 相对于输入文件夹的路径，作为图集描述中的帧名称
+    name: String,
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+/// 小图在图集中的位置
+struct Placement {
+    sprite: usize,
+    x: u32,
+    y: u32,
+}
+
+/// 一张图集
+#[derive(Default)]
+struct Page {
+    width: u32,
+    height: u32,
+    placements: Vec<Placement>,
+}
+
+/// TexturePacker JSON (Hash) 格式的图集描述
+#[derive(Serialize)]
+struct Atlas {
+    frames: BTreeMap<String, AtlasFrame>,
+    meta: AtlasMeta,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AtlasFrame {
+    frame: Rect,
+    rotated: bool,
+    trimmed: bool,
+    sprite_source_size: Rect,
+    source_size: Size,
+}
+
+#[derive(Serialize)]
+struct AtlasMeta {
+    app: &'static str,
+    version: &'static str,
+    image: String,
+    /// 图集的像素格式，图集写入为调色板模式的png
+    format: &'static str,
+    size: Size,
+    scale: &'static str,
+}
+
+#[derive(Serialize)]
+struct Rect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Serialize)]
+struct Size {
+    w: u32,
+    h: u32,
+}
+
+impl<'a> Pack<'a> {
+    pub fn new(
+        path: &'a Path,
+        output: Option<PathBuf>,
+        name: String,
+        max_size: u32,
+        padding: u32,
+        options: Options,
+    ) -> Pack<'a> {
+        Pack {
+            path,
+            output: output.unwrap_or_else(|| {
+                let stem = path.file_name().unwrap_or_default().to_string_lossy();
+                path.with_file_name(format!("{}_atlas", stem))
+            }),
+            name,
+            max_size,
+            padding,
+            options,
+        }
+    }
+
+    /// 遍历目录查找png图片，跳过重要性图以及导出的调色板和误差图
+    ///
+    /// `output`为图集输出文件夹的规范路径，输出文件夹在输入文件夹中时跳过，再次打包时不会把之前的图集当作小图
+    fn visit_pngs(dir: &Path, output: Option<&Path>, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                let is_output =
+                    output.is_some_and(|output| fs::canonicalize(&path).is_ok_and(|p| p == output));
+                if !is_output {
+                    Pack::visit_pngs(&path, output, paths)?;
+                }
+            } else if !is_generated(&path)
+                && !is_importance_map(&path)
+                && path
+                    .extension()
+                    .and_then(OsStr::to_str)
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("png"))
+            {
+                paths.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    /// 读取所有小图
+    fn read_sprites(&self) -> io::Result<Vec<Sprite>> {
+        let mut paths = vec![];
+        // 输出文件夹还不存在时不会在输入文件夹中
+        let output = fs::canonicalize(&self.output).ok();
+        Pack::visit_pngs(self.path, output.as_deref(), &mut paths)?;
+        paths.sort();
+        if paths.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, Error::NoInputFiles));
+        }
+
+        let mut sprites = Vec::with_capacity(paths.len());
+        for path in paths.iter() {
+            let (width, height, data) = read_rgba_png(path)?;
+            if width + self.padding * 2 > self.max_size || height + self.padding * 2 > self.max_size
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), Error::SpriteTooLarge),
+                ));
+            }
+            let name = path
+                .strip_prefix(self.path)
+                .unwrap_or(path)
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            sprites.push(Sprite {
+                name,
+                width,
+                height,
+                data,
+            });
+        }
+        Ok(sprites)
+    }
+
+    /// 按高度从大到小逐行排列小图，一张图集放不下时新建一张
+    fn layout(&self, sprites: &[Sprite]) -> Vec<Page> {
+        let mut order: Vec<usize> = (0..sprites.len()).collect();
+        order.sort_by(|&a, &b| {
+            sprites[b]
+                .height
+                .cmp(&sprites[a].height)
+                .then(sprites[b].width.cmp(&sprites[a].width))
+        });
+
+        let mut pages: Vec<Page> = vec![Page::default()];
+        // 当前行的位置和高度
+        let (mut x, mut y, mut row_height) = (0, 0, 0);
+        for index in order {
+            let sprite = &sprites[index];
+            let width = sprite.width + self.padding * 2;
+            let height = sprite.height + self.padding * 2;
+
+            // 当前行放不下，换行
+            if x + width > self.max_size {
+                x = 0;
+                y += row_height;
+                row_height = 0;
+            }
+            // 当前图集放不下，新建图集
+            if y + height > self.max_size {
+                pages.push(Page::default());
+                x = 0;
+                y = 0;
+                row_height = 0;
+            }
+
+            let page = pages.last_mut().unwrap();
+            page.placements.push(Placement {
+                sprite: index,
+                x: x + self.padding,
+                y: y + self.padding,
+            });
+            page.width = page.width.max(x + width);
+            page.height = page.height.max(y + height);
+            x += width;
+            row_height = row_height.max(height);
+        }
+        pages
+    }
+
+    /// 打包小图，生成图集图像和图集描述
+    pub fn run(&self) -> io::Result<()> {
+        println!("work Path: {}", self.path.display().to_string().green());
+        let sprites = self.read_sprites()?;
+        let pages = self.layout(&sprites);
+        fs::create_dir_all(&self.output)?;
+
+        // 输出调色板生成的进度
+        let (progress_sender, progress) = Progress::printer();
+        let attr = Pngquant::attributes(0, &self.options, DEF_QUALITY_MAX, progress_sender)
+            .map_err(invalid_data)?;
+
+        // 所有小图生成一个共享调色板，设置了固定调色板时直接使用固定调色板
+        let mut histogram = Pngquant::histogram(&attr, &self.options).map_err(invalid_data)?;
+        if self.options.palette.is_none() {
+            for sprite in sprites.iter() {
+                let pixels = rgb::FromSlice::as_rgba(&sprite.data[..]);
+                let mut image = imagequant::Image::new_borrowed(
+                    &attr,
+                    pixels,
+                    sprite.width as usize,
+                    sprite.height as usize,
+                    0.0,
+                )
+                .map_err(invalid_data)?;
+                histogram
+                    .add_image(&attr, &mut image)
+                    .map_err(invalid_data)?;
+            }
+            // 图集的空白区域需要完全透明的颜色
+            histogram
+                .add_fixed_color(imagequant::RGBA::new(0, 0, 0, 0), 0.0)
+                .map_err(invalid_data)?;
+        }
+        let mut res = histogram.quantize(&attr).map_err(invalid_data)?;
+        res.set_dithering_level(self.options.dithering_level.unwrap_or(1.0))
+            .map_err(invalid_data)?;
+
+        for (page_index, page) in pages.iter().enumerate() {
+            // 将小图绘制到图集画布上
+            let stride = page.width as usize * 4;
+            let mut canvas = vec![0; stride * page.height as usize];
+            for placement in page.placements.iter() {
+                let sprite = &sprites[placement.sprite];
+                let row = sprite.width as usize * 4;
+                for y in 0..sprite.height as usize {
+                    let dst = (placement.y as usize + y) * stride + placement.x as usize * 4;
+                    canvas[dst..dst + row].copy_from_slice(&sprite.data[y * row..(y + 1) * row]);
+                }
+            }
+
+            let pixels = rgb::FromSlice::as_rgba(&canvas[..]);
+            let mut image = imagequant::Image::new_borrowed(
+                &attr,
+                pixels,
+                page.width as usize,
+                page.height as usize,
+                0.0,
+            )
+            .map_err(invalid_data)?;
+            let (palette, pixels) = res.remapped(&mut image).map_err(invalid_data)?;
+
+            let image_name = format!("{}_{}.png", self.name, page_index);
+            let image_path = self.output.join(&image_name);
+            write_indexed_png(
+                &image_path,
+                page.width,
+                page.height,
+                &palette,
+                &pixels,
+                self.options.compression,
+            )?;
+            // 同时导出调色板文件
+            if let Some(format) = self.options.export_palette {
+                write_palette(&format.export_path(&image_path), format, &palette)?;
+            }
+
+            let frames = page
+                .placements
+                .iter()
+                .map(|placement| {
+                    let sprite = &sprites[placement.sprite];
+                    let frame = AtlasFrame {
+                        frame: Rect {
+                            x: placement.x,
+                            y: placement.y,
+                            w: sprite.width,
+                            h: sprite.height,
+                        },
+                        rotated: false,
+                        trimmed: false,
+                        sprite_source_size: Rect {
+                            x: 0,
+                            y: 0,
+                            w: sprite.width,
+                            h: sprite.height,
+                        },
+                        source_size: Size {
+                            w: sprite.width,
+                            h: sprite.height,
+                        },
+                    };
+                    (sprite.name.clone(), frame)
+                })
+                .collect();
+            let atlas = Atlas {
+                frames,
+                meta: AtlasMeta {
+                    app: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                    image: image_name,
+                    format: "indexed",
+                    size: Size {
+                        w: page.width,
+                        h: page.height,
+                    },
+                    scale: "1",
+                },
+            };
+            let file = File::create(
+                self.output
+                    .join(format!("{}_{}.json", self.name, page_index)),
+            )?;
+            serde_json::to_writer_pretty(BufWriter::new(file), &atlas)?;
+        }
+
+        // 关闭进度通道，等待进度输出结束
+        drop(res);
+        drop(histogram);
+        drop(attr);
+        progress.join().unwrap();
+
+        println!("pack sprites: {}", sprites.len().to_string().green());
+        println!("atlas pages: {}", pages.len().to_string().green());
+        println!("output path: {}", self.output.display().to_string().green());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack(max_size: u32, padding: u32) -> Pack<'static> {
+        Pack::new(
+            Path::new("sprites"),
+            None,
+            "atlas".to_string(),
+            max_size,
+            padding,
+            Options::default(),
+        )
+    }
+
+    fn sprite(width: u32, height: u32) -> Sprite {
+        Sprite {
+            name: String::new(),
+            width,
+            height,
+            data: Vec::new(),
+        }
+    }
+
+    /// 每张图集中小图的下标和位置
+    fn positions(pages: &[Page]) -> Vec<Vec<(usize, u32, u32)>> {
+        pages
+            .iter()
+            .map(|page| {
+                page.placements
+                    .iter()
+                    .map(|placement| (placement.sprite, placement.x, placement.y))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn layout_wraps_rows() {
+        // 高的小图排在前面，第三张放不下时换行到当前行最高的小图下面
+        let sprites = [sprite(4, 3), sprite(4, 4), sprite(4, 2)];
+        let pages = pack(10, 0).layout(&sprites);
+        assert_eq!(positions(&pages), [vec![(1, 0, 0), (0, 4, 0), (2, 0, 4)]]);
+        assert_eq!((pages[0].width, pages[0].height), (8, 6));
+    }
+
+    #[test]
+    fn layout_overflows_pages() {
+        let sprites = [sprite(6, 6), sprite(6, 6), sprite(2, 2)];
+        let pages = pack(10, 0).layout(&sprites);
+        // 第二张换行后高度超出图集，新建图集；小图在新图集的当前行继续排列
+        assert_eq!(
+            positions(&pages),
+            [vec![(0, 0, 0)], vec![(1, 0, 0), (2, 6, 0)]]
+        );
+        assert_eq!((pages[0].width, pages[0].height), (6, 6));
+        assert_eq!((pages[1].width, pages[1].height), (8, 6));
+    }
+
+    #[test]
+    fn layout_padding() {
+        // 每张小图四周各留出间距，间距计入换行和图集尺寸
+        let sprites = [sprite(4, 4), sprite(4, 4), sprite(4, 4)];
+        let pages = pack(12, 1).layout(&sprites);
+        assert_eq!(positions(&pages), [vec![(0, 1, 1), (1, 7, 1), (2, 1, 7)]]);
+        assert_eq!((pages[0].width, pages[0].height), (12, 12));
+    }
+
+    #[test]
+    fn visit_skips_output_dir() {
+        let dir = std::env::temp_dir().join("png-palettes-optimization-pack-visit");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("icons")).unwrap();
+        fs::create_dir_all(dir.join("atlas")).unwrap();
+        for path in ["hero.png", "icons/coin.png", "atlas/atlas_0.png"] {
+            fs::write(dir.join(path), b"").unwrap();
+        }
+
+        let output = fs::canonicalize(dir.join("atlas")).unwrap();
+        let mut paths = vec![];
+        Pack::visit_pngs(&dir, Some(&output), &mut paths).unwrap();
+        paths.sort();
+        assert_eq!(paths, [dir.join("hero.png"), dir.join("icons/coin.png")]);
+
+        // 没有输出文件夹时包括所有子文件夹
+        let mut paths = vec![];
+        Pack::visit_pngs(&dir, None, &mut paths).unwrap();
+        assert_eq!(paths.len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,411 @@
+use super::image::{invalid_data, read_rgba_png, write_indexed_png};
+use crate::error::Error;
+use imagequant::RGBA;
+use png::{ColorType, Compression, Decoder};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// 调色板文件格式
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PaletteFormat {
+    /// GIMP调色板，不保存alpha
+    Gpl,
+    /// Adobe Color Table，只保存一个完全透明的颜色索引
+    Act,
+    /// JASC-PAL调色板，不保存alpha
+    Pal,
+    /// rgba颜色数组
+    Json,
+    /// 16x16的调色板模式png色板，每个像素一个颜色
+    Png,
+}
+
+impl PaletteFormat {
+    /// 根据文件扩展名判断调色板格式
+    pub fn from_path(path: &Path) -> Option<PaletteFormat> {
+        let extension = path.extension().and_then(OsStr::to_str)?;
+        match extension.to_ascii_lowercase().as_str() {
+            "gpl" => Some(PaletteFormat::Gpl),
+            "act" => Some(PaletteFormat::Act),
+            "pal" => Some(PaletteFormat::Pal),
+            "json" => Some(PaletteFormat::Json),
+            "png" => Some(PaletteFormat::Png),
+            _ => None,
+        }
+    }
+
+    /// 调色板文件扩展名
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PaletteFormat::Gpl => "gpl",
+            PaletteFormat::Act => "act",
+            PaletteFormat::Pal => "pal",
+            PaletteFormat::Json => "json",
+            PaletteFormat::Png => "png",
+        }
+    }
+
+    /// 优化后图像的调色板导出路径，`image.png`导出为`image.palette.gpl`
+    pub fn export_path(&self, path: &Path) -> PathBuf {
+        path.with_extension(format!("palette.{}", self.extension()))
+    }
+}
+
+/// json调色板中的颜色
+#[derive(Serialize, Deserialize)]
+struct JsonColor {
+    r: u8,
+    g: u8,
+    b: u8,
+    #[serde(default = "opaque")]
+    a: u8,
+}
+
+fn opaque() -> u8 {
+    255
+}
+
+/// 调色板最多256个颜色
+const MAX_COLORS: usize = 256;
+
+/// 读取调色板文件，根据扩展名判断格式
+pub fn read_palette(path: &Path) -> io::Result<Vec<RGBA>> {
+    let format = PaletteFormat::from_path(path).ok_or_else(|| invalid_palette(path))?;
+    let palette = match format {
+        PaletteFormat::Gpl => read_gpl(&fs::read_to_string(path)?),
+        PaletteFormat::Act => read_act(&fs::read(path)?),
+        PaletteFormat::Pal => read_pal(&fs::read_to_string(path)?),
+        PaletteFormat::Json => {
+            let colors: Vec<JsonColor> =
+                serde_json::from_reader(BufReader::new(File::open(path)?))?;
+            Some(
+                colors
+                    .into_iter()
+                    .map(|color| RGBA::new(color.r, color.g, color.b, color.a))
+                    .collect(),
+            )
+        }
+        PaletteFormat::Png => Some(read_swatch(path)?),
+    };
+    match palette {
+        Some(palette) if !palette.is_empty() && palette.len() <= MAX_COLORS => Ok(palette),
+        _ => Err(invalid_palette(path)),
+    }
+}
+
+/// 将调色板写入文件
+pub fn write_palette(path: &Path, format: PaletteFormat, palette: &[RGBA]) -> io::Result<()> {
+    if format == PaletteFormat::Png {
+        return write_swatch(path, palette);
+    }
+
+    let mut w = BufWriter::new(File::create(path)?);
+    match format {
+        PaletteFormat::Gpl => {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            writeln!(w, "GIMP Palette")?;
+            writeln!(w, "Name: {}", name)?;
+            writeln!(w, "Columns: 16")?;
+            writeln!(w, "#")?;
+            for color in palette.iter() {
+                writeln!(
+                    w,
+                    "{:3} {:3} {:3}\t#{:02x}{:02x}{:02x}",
+                    color.r, color.g, color.b, color.r, color.g, color.b
+                )?;
+            }
+        }
+        PaletteFormat::Act => {
+            // 固定256个rgb颜色，之后是颜色数量和透明色索引，没有透明色时为0xFFFF
+            let mut data = vec![0; MAX_COLORS * 3];
+            for (index, color) in palette.iter().take(MAX_COLORS).enumerate() {
+                data[index * 3..index * 3 + 3].copy_from_slice(&[color.r, color.g, color.b]);
+            }
+            let transparent = palette
+                .iter()
+                .position(|color| color.a == 0)
+                .map_or(0xFFFF, |index| index as u16);
+            data.extend_from_slice(&(palette.len().min(MAX_COLORS) as u16).to_be_bytes());
+            data.extend_from_slice(&transparent.to_be_bytes());
+            w.write_all(&data)?;
+        }
+        PaletteFormat::Pal => {
+            writeln!(w, "JASC-PAL")?;
+            writeln!(w, "0100")?;
+            writeln!(w, "{}", palette.len())?;
+            for color in palette.iter() {
+                writeln!(w, "{} {} {}", color.r, color.g, color.b)?;
+            }
+        }
+        PaletteFormat::Json => {
+            let colors: Vec<JsonColor> = palette
+                .iter()
+                .map(|color| JsonColor {
+                    r: color.r,
+                    g: color.g,
+                    b: color.b,
+                    a: color.a,
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut w, &colors)?;
+        }
+        PaletteFormat::Png => unreachable!(),
+    }
+    w.flush()
+}
+
+/// 解析`#RRGGBBAA`或`#RRGGBB`格式的颜色，`#`可以省略
+pub fn parse_color(s: &str) -> Result<RGBA, String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    let channel = |index: usize| {
+        hex.get(index * 2..index * 2 + 2)
+            .and_then(|value| u8::from_str_radix(value, 16).ok())
+    };
+    let color = match hex.len() {
+        6 => channel(0)
+            .zip(channel(1))
+            .zip(channel(2))
+            .map(|((r, g), b)| RGBA::new(r, g, b, 255)),
+        8 => channel(0)
+            .zip(channel(1))
+            .zip(channel(2))
+            .zip(channel(3))
+            .map(|(((r, g), b), a)| RGBA::new(r, g, b, a)),
+        _ => None,
+    };
+    color.ok_or_else(|| format!("invalid color `{}`", s))
+}
+
+fn invalid_palette(path: &Path) -> io::Error {
+    invalid_data(format!("{}: {}", path.display(), Error::InvalidPalette))
+}
+
+/// 解析以空白分隔的rgb颜色行
+fn parse_rgb(line: &str) -> Option<RGBA> {
+    let mut values = line.split_whitespace().map(str::parse::<u8>);
+    match (values.next(), values.next(), values.next()) {
+        (Some(Ok(r)), Some(Ok(g)), Some(Ok(b))) => Some(RGBA::new(r, g, b, 255)),
+        _ => None,
+    }
+}
+
+/// 读取GIMP调色板，跳过文件头和注释，颜色行之后的名称忽略
+fn read_gpl(text: &str) -> Option<Vec<RGBA>> {
+    let mut lines = text.lines();
+    if lines.next()?.trim() != "GIMP Palette" {
+        return None;
+    }
+    lines
+        .map(str::trim)
+        .filter(|line| {
+            !line.is_empty()
+                && !line.starts_with('#')
+                && !line.starts_with("Name:")
+                && !line.starts_with("Columns:")
+        })
+        .map(parse_rgb)
+        .collect()
+}
+
+/// 读取Adobe Color Table，772字节的文件包含颜色数量和透明色索引
+fn read_act(data: &[u8]) -> Option<Vec<RGBA>> {
+    if data.len() < MAX_COLORS * 3 {
+        return None;
+    }
+    let (count, transparent) = if data.len() >= MAX_COLORS * 3 + 4 {
+        let count = u16::from_be_bytes([data[768], data[769]]) as usize;
+        let transparent = u16::from_be_bytes([data[770], data[771]]) as usize;
+        (count.clamp(1, MAX_COLORS), transparent)
+    } else {
+        (MAX_COLORS, 0xFFFF)
+    };
+    Some(
+        data[..count * 3]
+            .chunks_exact(3)
+            .enumerate()
+            .map(|(index, c)| {
+                let alpha = if index == transparent { 0 } else { 255 };
+                RGBA::new(c[0], c[1], c[2], alpha)
+            })
+            .collect(),
+    )
+}
+
+/// 读取JASC-PAL调色板
+fn read_pal(text: &str) -> Option<Vec<RGBA>> {
+    let mut lines = text.lines().map(str::trim);
+    if lines.next()? != "JASC-PAL" {
+        return None;
+    }
+    lines.next()?;
+    let count = lines.next()?.parse::<usize>().ok()?;
+    let palette: Option<Vec<RGBA>> = lines
+        .filter(|line| !line.is_empty())
+        .take(count)
+        .map(parse_rgb)
+        .collect();
+    palette.filter(|palette| palette.len() == count)
+}
+
+/// 读取png色板，调色板模式直接使用`PLTE`和`tRNS`，其他模式按像素顺序收集不重复的颜色
+fn read_swatch(path: &Path) -> io::Result<Vec<RGBA>> {
+    let reader = Decoder::new(File::open(path)?).read_info()?;
+    let info = reader.info();
+    if info.color_type == ColorType::Indexed {
+        if let Some(plte) = &info.palette {
+            let trns = info.trns.as_deref().unwrap_or(&[]);
+            return Ok(plte
+                .chunks_exact(3)
+                .enumerate()
+                .map(|(index, c)| RGBA::new(c[0], c[1], c[2], *trns.get(index).unwrap_or(&255)))
+                .collect());
+        }
+    }
+
+    let (_, _, data) = read_rgba_png(path)?;
+    let mut palette: Vec<RGBA> = vec![];
+    for c in data.chunks_exact(4) {
+        let color = RGBA::new(c[0], c[1], c[2], c[3]);
+        if !palette.contains(&color) {
+            palette.push(color);
+            if palette.len() > MAX_COLORS {
+                break;
+            }
+        }
+    }
+    Ok(palette)
+}
+
+/// 写入16x16的调色板模式png色板，第`i`个像素为第`i`个颜色，多余的像素使用最后一个颜色
+fn write_swatch(path: &Path, palette: &[RGBA]) -> io::Result<()> {
+    let last = palette.len().clamp(1, MAX_COLORS) - 1;
+    let pixels: Vec<u8> = (0..MAX_COLORS).map(|index| index.min(last) as u8).collect();
+    write_indexed_png(path, 16, 16, palette, &pixels, Compression::Best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palette() -> Vec<RGBA> {
+        vec![
+            RGBA::new(0, 0, 0, 0),
+            RGBA::new(255, 0, 0, 255),
+            RGBA::new(18, 52, 86, 255),
+        ]
+    }
+
+    /// 写入临时文件后再读取
+    fn round_trip(format: PaletteFormat, palette: &[RGBA]) -> Vec<RGBA> {
+        let path = std::env::temp_dir().join(format!(
+            "png-palettes-optimization-round-trip.{}",
+            format.extension()
+        ));
+        write_palette(&path, format, palette).unwrap();
+        let result = read_palette(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn opaque(palette: &[RGBA]) -> Vec<RGBA> {
+        palette
+            .iter()
+            .map(|color| RGBA::new(color.r, color.g, color.b, 255))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_keeps_alpha() {
+        assert_eq!(round_trip(PaletteFormat::Json, &palette()), palette());
+        assert_eq!(round_trip(PaletteFormat::Png, &palette()), palette());
+        // act只保存透明色索引
+        assert_eq!(round_trip(PaletteFormat::Act, &palette()), palette());
+    }
+
+    #[test]
+    fn round_trip_drops_alpha() {
+        assert_eq!(
+            round_trip(PaletteFormat::Gpl, &palette()),
+            opaque(&palette())
+        );
+        assert_eq!(
+            round_trip(PaletteFormat::Pal, &palette()),
+            opaque(&palette())
+        );
+    }
+
+    #[test]
+    fn read_gpl_skips_header_and_names() {
+        let text = "GIMP Palette\nName: brand\nColumns: 4\n#\n  0  0  0\tBlack\n255 128 1 orange\n";
+        assert_eq!(
+            read_gpl(text),
+            Some(vec![RGBA::new(0, 0, 0, 255), RGBA::new(255, 128, 1, 255)])
+        );
+        assert_eq!(read_gpl("0 0 0\n"), None);
+        assert_eq!(read_gpl("GIMP Palette\n0 0 256\n"), None);
+    }
+
+    #[test]
+    fn read_act_count_and_transparent() {
+        let mut data = vec![0; MAX_COLORS * 3];
+        data[3..6].copy_from_slice(&[1, 2, 3]);
+        // 没有颜色数量时读取全部256个颜色
+        let palette = read_act(&data).unwrap();
+        assert_eq!(palette.len(), MAX_COLORS);
+        assert_eq!(palette[1], RGBA::new(1, 2, 3, 255));
+
+        data.extend_from_slice(&[0, 2, 0, 0]);
+        assert_eq!(
+            read_act(&data),
+            Some(vec![RGBA::new(0, 0, 0, 0), RGBA::new(1, 2, 3, 255)])
+        );
+        assert_eq!(read_act(&data[..100]), None);
+    }
+
+    #[test]
+    fn read_pal_checks_count() {
+        let text = "JASC-PAL\n0100\n2\n1 2 3\n4 5 6\n";
+        assert_eq!(
+            read_pal(text),
+            Some(vec![RGBA::new(1, 2, 3, 255), RGBA::new(4, 5, 6, 255)])
+        );
+        assert_eq!(read_pal("JASC-PAL\n0100\n3\n1 2 3\n4 5 6\n"), None);
+        assert_eq!(read_pal("GIMP Palette\n"), None);
+    }
+
+    #[test]
+    fn read_palette_rejects_unknown_and_empty() {
+        let dir = std::env::temp_dir();
+        assert!(read_palette(&dir.join("palette.txt")).is_err());
+
+        let path = dir.join("png-palettes-optimization-empty.json");
+        fs::write(&path, "[]").unwrap();
+        let result = read_palette(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_color_hex() {
+        assert_eq!(parse_color("#ff8000"), Ok(RGBA::new(255, 128, 0, 255)));
+        assert_eq!(parse_color("FF800040"), Ok(RGBA::new(255, 128, 0, 64)));
+        for s in ["", "#fff", "#ff800", "#gg0000", "#ff8000401"] {
+            assert!(parse_color(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn export_path_keeps_stem() {
+        assert_eq!(
+            PaletteFormat::Gpl.export_path(Path::new("a/hero.png")),
+            Path::new("a/hero.palette.gpl")
+        );
+        assert_eq!(
+            PaletteFormat::from_path(Path::new("brand.ACT")),
+            Some(PaletteFormat::Act)
+        );
+    }
+}
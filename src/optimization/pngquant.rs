@@ -1,14 +1,144 @@
+use colored::*;
 use imagequant::Histogram;
-use png::{ColorType, Compression, Decoder, Reader};
-use std::{fs::File, io::BufWriter, path::Path, sync::mpsc::SyncSender};
+use png::{AnimationControl, BlendOp, ColorType, Decoder, DisposeOp, Reader};
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use std::{fs, fs::File, path::Path};
 
-use super::Frame;
-use crate::{error::Error, PROGRESS_CONSTANT};
+use super::diff::{heatmap, pixel_errors, side_by_side};
+use super::frame::Canvas;
+use super::gif_writer::write_gif;
+use super::image::{
+    indexed_bit_depth, pack_indices, read_rgba_frames, read_rgba_png, write_rgba_png,
+};
+use super::palette::write_palette;
+use super::ssim::ssim;
+use super::{Frame, Options};
+use crate::{error::Error, interrupt, PROGRESS_CONSTANT};
+
+/// 默认优化的最大质量
+pub const DEF_QUALITY_MAX: u8 = 60;
+
+/// 没有设置速度时imagequant使用的默认速度
+const DEF_SPEED: u8 = 4;
+
+/// 重要性图的扩展名，`hero.png`的重要性图为同级的`hero.importance.png`
+const IMPORTANCE_MAP_EXTENSION: &str = "importance.png";
+
+/// 工具自己写入的png文件的扩展名：png格式的导出调色板，`--diff-dir`的误差热力图和对比图
+const GENERATED_EXTENSIONS: [&str; 3] = ["palette.png", "heatmap.png", "compare.png"];
+
+/// 是否是重要性图文件，重要性图本身不需要优化
+pub fn is_importance_map(path: &Path) -> bool {
+    has_compound_extension(path, IMPORTANCE_MAP_EXTENSION)
+}
+
+/// 是否是工具自己写入的png文件，扫描工作路径时跳过，再次运行时不会被当作图像处理
+pub fn is_generated(path: &Path) -> bool {
+    GENERATED_EXTENSIONS
+        .iter()
+        .any(|extension| has_compound_extension(path, extension))
+}
+
+/// 文件名是否以`.{extension}`结尾，不区分大小写
+fn has_compound_extension(path: &Path, extension: &str) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| {
+            name.to_ascii_lowercase()
+                .ends_with(&format!(".{}", extension))
+        })
+}
 
 #[derive(Debug)]
 pub struct Progress {
     pub id: usize,
     pub value: f32,
+    /// 需要在主线程输出的提示，例如忽略的重要性图，有提示时不改变进度
+    pub warning: Option<String>,
+}
+
+/// 发送进度的回调，在生成调色板和编码的线程中调用
+pub type ProgressSender = Arc<dyn Fn(Progress) + Send + Sync>;
+
+impl Progress {
+    /// 创建单个任务使用的进度回调，在终端输出进度百分比，回调全部释放后线程结束
+    pub fn printer() -> (ProgressSender, JoinHandle<()>) {
+        let (sender, progress_receiver) = mpsc::sync_channel::<Progress>(1);
+        let handle = thread::spawn(move || {
+            for progress in progress_receiver {
+                if let Some(warning) = progress.warning {
+                    println!("\r{}", warning);
+                    continue;
+                }
+                let perc = (progress.value / PROGRESS_CONSTANT * 100.0).trunc() as usize;
+                print!("\rprocessing data: {}", format!("{}%", perc).green());
+                io::stdout().flush().unwrap();
+            }
+            println!();
+        });
+        let progress_sender: ProgressSender = Arc::new(move |progress| {
+            let _ = sender.send(progress);
+        });
+        (progress_sender, handle)
+    }
+}
+
+/// 编码结果
+#[derive(Default)]
+struct Encoded {
+    /// 编码后的png数据
+    data: Vec<u8>,
+    /// 使用的调色板
+    palette: Vec<imagequant::RGBA>,
+    /// 量化的均方误差
+    error: Option<f64>,
+    /// 量化达到的质量，`0-100`
+    quality: Option<u8>,
+    /// apng每帧的索引数据，用于导出gif，普通png为空
+    frame_pixels: Vec<Option<Vec<u8>>>,
+}
+
+/// 分配文件大小预算时一组参数的试编码结果
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Probe {
+    /// 最大颜色数量
+    pub colors: u32,
+    /// 最高质量，`None`时使用设置的最高质量
+    pub quality_max: Option<u8>,
+    /// 编码后的大小
+    pub size: u64,
+    /// 所有像素的总误差，为均方误差乘以像素数量，apng为所有帧的像素数量
+    pub error: f64,
+}
+
+/// 均方误差转换为峰值信噪比，单位dB
+pub fn psnr(mse: f64) -> f64 {
+    if mse <= 0.0 {
+        return f64::INFINITY;
+    }
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// 输出达到的质量、均方误差、峰值信噪比以及结构相似度
+pub fn quality_report(quality: Option<u8>, mse: Option<f64>, ssim: Option<f64>) -> String {
+    let mut report = match (quality, mse) {
+        (Some(quality), Some(mse)) => format!(
+            "quality: {} MSE: {:.2} PSNR: {:.2}dB",
+            quality,
+            mse,
+            psnr(mse)
+        ),
+        _ => "quality: unknown".to_string(),
+    };
+    if let Some(ssim) = ssim {
+        report.push_str(&format!(" SSIM: {:.4}", ssim));
+    }
+    report
 }
 
 /// PNG优化结构体
@@ -16,69 +146,71 @@ pub struct Pngquant<'a> {
     id: usize,
     /// png文件路径
     pub path: &'a Path,
-    reader: Reader<File>,
+    /// 图像宽度
+    width: u32,
+    /// 图像高度
+    height: u32,
+    /// apng动画信息
+    animation_control: Option<AnimationControl>,
     /// 图像数据
     bytes: Option<Vec<imagequant::RGBA>>,
     /// apng 帧数据
     frames: Option<Vec<Frame>>,
     histogram: Option<Histogram>,
-    /// 优化参数设置
-    imagequant_attr: Option<imagequant::Attributes>,
+    /// 重要性图，每个像素一个权重，值越大的区域分配越多的调色板颜色
+    importance_map: Option<Vec<u8>>,
     /// 默认优化的最大质量
     def_quality_max: u8,
-    /// 平滑图像参数
-    dithering_level: Option<f32>,
+    /// 优化参数
+    options: Options,
     /// 进度发送
-    progress_sender: SyncSender<Progress>,
+    progress_sender: ProgressSender,
     /// 源文件大小
     pub original_size: Option<u64>,
     /// 压缩文件大小
     pub size: Option<u64>,
+    /// 量化达到的质量
+    pub quality: Option<u8>,
+    /// 量化的均方误差
+    pub mse: Option<f64>,
+    /// 写入的文件与源图像的结构相似度
+    pub ssim: Option<f64>,
 }
 
 impl<'a> Pngquant<'a> {
     pub fn new(
         id: usize,
         path: &'a Path,
-        speed: Option<u8>,
-        quality_min: Option<u8>,
-        quality_max: Option<u8>,
-        dithering_level: Option<f32>,
-        progress_sender: SyncSender<Progress>,
+        options: &Options,
+        progress_sender: ProgressSender,
     ) -> Result<Pngquant<'a>, Error> {
-        let file = File::open(path).unwrap();
-        let original_size = file.metadata().unwrap().len();
+        let file = File::open(path).map_err(Error::io(path))?;
+        let original_size = file.metadata().map_err(Error::io(path))?.len();
         let decoder = Decoder::new(file);
-        let reader = decoder.read_info().unwrap();
+        let reader = decoder.read_info().map_err(Error::decode(path))?;
         let info = reader.info();
-        let def_quality_max: u8 = 60;
         // 根据颜色模式实例化不同的优化结构体，目前只支持优化Rgba模式的png图像
         match info.color_type {
             ColorType::Rgba => {
                 // 是否是apng
                 if info.is_animated() {
-                    Ok(Pngquant::decoder_rgba_apng(
+                    Pngquant::decoder_rgba_apng(
                         id,
                         path,
                         reader,
-                        speed,
-                        quality_min,
-                        quality_max,
-                        def_quality_max,
-                        dithering_level,
+                        options,
                         progress_sender,
                         original_size,
-                    ))
+                    )
                 } else {
-                    Ok(Pngquant::decoder_rgba_png(
+                    Pngquant::decoder_rgba_png(
                         id,
                         path,
                         reader,
-                        def_quality_max,
-                        dithering_level,
+                        options,
                         progress_sender,
                         original_size,
-                    ))
+                    )
                 }
             }
             // ColorType::Indexed => Err(Error::UnsupportedColorMode),
@@ -91,28 +223,42 @@ impl<'a> Pngquant<'a> {
         id: usize,
         path: &'a Path,
         mut reader: Reader<File>,
-        def_quality_max: u8,
-        dithering_level: Option<f32>,
-        progress_sender: SyncSender<Progress>,
+        options: &Options,
+        progress_sender: ProgressSender,
         original_size: u64,
-    ) -> Pngquant<'a> {
-        let mut buf = vec![0; reader.output_buffer_size()];
-        let output_info = reader.next_frame(&mut buf).unwrap();
-        let bytes = Some(rgb::FromSlice::as_rgba(&buf[..output_info.buffer_size()]).to_vec());
-        Pngquant {
+    ) -> Result<Pngquant<'a>, Error> {
+        // 直接解码到rgba像素数组，不需要再复制一份图像数据
+        let mut buf = vec![imagequant::RGBA::default(); reader.output_buffer_size() / 4];
+        let output_info = reader
+            .next_frame(rgb::ComponentBytes::as_bytes_mut(&mut buf[..]))
+            .map_err(Error::decode(path))?;
+        buf.truncate(output_info.buffer_size() / 4);
+        let bytes = Some(buf);
+        let info = reader.info();
+        let importance_map = Pngquant::importance_map(path, info.width, info.height)
+            .unwrap_or_else(|warning| {
+                Pngquant::send_warning(&progress_sender, id, warning);
+                None
+            });
+        Ok(Pngquant {
             id,
             path,
-            reader,
+            width: info.width,
+            height: info.height,
+            animation_control: None,
             bytes,
             frames: None,
             histogram: None,
-            imagequant_attr: None,
-            def_quality_max,
-            dithering_level,
+            importance_map,
+            def_quality_max: DEF_QUALITY_MAX,
+            options: options.clone(),
             progress_sender,
             original_size: Some(original_size),
             size: None,
-        }
+            quality: None,
+            mse: None,
+            ssim: None,
+        })
     }
 
     /// 解码rgba的apng图像数据
@@ -120,314 +266,906 @@ impl<'a> Pngquant<'a> {
         id: usize,
         path: &'a Path,
         mut reader: Reader<File>,
-        speed: Option<u8>,
-        quality_min: Option<u8>,
-        quality_max: Option<u8>,
-        def_quality_max: u8,
-        dithering_level: Option<f32>,
-        progress_sender: SyncSender<Progress>,
+        options: &Options,
+        progress_sender: ProgressSender,
         original_size: u64,
-    ) -> Pngquant<'a> {
-        let mut frames: Vec<Frame> = vec![];
+    ) -> Result<Pngquant<'a>, Error> {
+        let info = reader.info();
+        let (width, height) = (info.width, info.height);
+        let num_plays = info
+            .animation_control
+            .map(|animation| animation.num_plays)
+            .unwrap_or(0);
+        // 循环读取帧数据
+        let frames = Frame::read_frames(&mut reader).map_err(Error::decode(path))?;
+        Pngquant::from_frames(
+            id,
+            path,
+            frames,
+            width,
+            height,
+            num_plays,
+            options,
+            progress_sender,
+            original_size,
+        )
+    }
+
+    /// 使用帧数据创建apng优化结构体，所有帧生成一个共享调色板
+    ///
+    /// `path`为编码后写入的apng文件路径，`num_plays`为循环次数，0为无限循环
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_frames(
+        id: usize,
+        path: &'a Path,
+        frames: Vec<Frame>,
+        width: u32,
+        height: u32,
+        num_plays: u32,
+        options: &Options,
+        progress_sender: ProgressSender,
+        original_size: u64,
+    ) -> Result<Pngquant<'a>, Error> {
         // 因为要为多个图像生成一个共享调色板，所以要提前生成
+        let attr = Pngquant::attributes(id, options, DEF_QUALITY_MAX, progress_sender.clone())
+            .map_err(Error::quantization(path))?;
+
+        let importance_map =
+            Pngquant::importance_map(path, width, height).unwrap_or_else(|warning| {
+                Pngquant::send_warning(&progress_sender, id, warning);
+                None
+            });
+
+        // 为多个图像生成一个共享调色板，设置了固定调色板时直接使用固定调色板
+        let mut histogram =
+            Pngquant::histogram(&attr, options).map_err(Error::quantization(path))?;
+        if options.palette.is_none() {
+            Pngquant::add_frames(
+                &attr,
+                &mut histogram,
+                &frames,
+                width,
+                height,
+                &importance_map,
+            )
+            .map_err(Error::quantization(path))?;
+        }
+
+        Ok(Pngquant {
+            id,
+            path,
+            width,
+            height,
+            animation_control: Some(AnimationControl {
+                num_frames: frames.len() as u32,
+                num_plays,
+            }),
+            bytes: None,
+            frames: Some(frames),
+            histogram: Some(histogram),
+            importance_map,
+            def_quality_max: DEF_QUALITY_MAX,
+            options: options.clone(),
+            progress_sender,
+            original_size: Some(original_size),
+            size: None,
+            quality: None,
+            mse: None,
+            ssim: None,
+        })
+    }
+
+    /// 生成调色板的参数设置，`def_quality_max`为没有设置最大质量时使用的默认值
+    pub fn attributes(
+        id: usize,
+        options: &Options,
+        def_quality_max: u8,
+        progress_sender: ProgressSender,
+    ) -> Result<imagequant::Attributes, imagequant::Error> {
         let mut attr = imagequant::new();
-        let sender = progress_sender.clone();
 
-        // 调色板生成进度更新回调
+        // 调色板生成进度更新回调，只在调色板生成时调用，
+        // 重新映射和抖动的回调需要设置在量化结果上，见`set_abort_callback`
+        let deadline = options.deadline;
         attr.set_progress_callback(move |progress| {
             // 将进度发送到主线程
-            sender
-                .send(Progress {
-                    id,
-                    value: progress,
-                })
-                .unwrap();
-            imagequant::ControlFlow::Continue
+            progress_sender(Progress {
+                id,
+                value: progress,
+                warning: None,
+            });
+            // 收到中断信号或者超过文件的截止时间时中止调色板生成
+            Pngquant::control_flow(interrupt::flag(), deadline)
         });
 
         // 设置压缩算法执行速度
-        if let Some(speed) = speed {
-            attr.set_speed(speed as i32).unwrap();
+        if let Some(speed) = options.speed {
+            attr.set_speed(speed as i32)?;
         }
 
-        // 默认质量的参数设置
-        match (quality_min, quality_max) {
-            (Some(quality_min), Some(quality_max)) => {
-                attr.set_quality(quality_min, quality_max).unwrap()
-            }
-            (Some(quality_min), None) => attr.set_quality(quality_min, def_quality_max).unwrap(),
-            (None, Some(quality_max)) => attr.set_quality(0, quality_max).unwrap(),
-            (None, None) => attr.set_quality(0, def_quality_max).unwrap(),
+        // 使用固定调色板时不再限制质量，调色板颜色数量与固定调色板一致
+        if let Some(palette) = &options.palette {
+            attr.set_max_colors(palette.len().max(2) as u32)?;
+            attr.set_quality(0, 100)?;
+            return Ok(attr);
         }
 
-        // 为多个图像生成一个共享调色板
-        let mut histogram = imagequant::Histogram::new(&attr);
-        // 循环读取帧数据
-        loop {
-            let mut buf = vec![0; reader.output_buffer_size()];
+        // 调色板的最大颜色数量
+        if let Some(colors) = options.colors {
+            attr.set_max_colors(colors)?;
+        }
 
-            // 是否是最后一帧
-            if let Result::Ok(output) = reader.next_frame(&mut buf) {
-                let info = reader.info();
-                let bytes = &buf[..output.buffer_size()];
-                if let Some(control) = info.frame_control() {
-                    // 将每帧数据保存
-                    let frame = Frame::new(
-                        bytes.to_vec(),
-                        control.width,
-                        control.height,
-                        control.x_offset,
-                        control.y_offset,
-                        control.delay_num,
-                        control.delay_den,
-                        control.dispose_op,
-                        control.blend_op,
-                    );
-                    let pixels = rgb::FromSlice::as_rgba(bytes);
-                    let mut image = imagequant::Image::new_borrowed(
-                        &attr,
-                        pixels,
-                        control.width as usize,
-                        control.height as usize,
-                        0.0,
-                    )
-                    .unwrap();
-                    // 保存图像直方图，用于稍后的调色板生成
-                    histogram.add_image(&attr, &mut image).unwrap();
-                    frames.push(frame);
-                }
-            } else {
-                break;
-            }
+        // 默认质量的参数设置，最低质量高于最大质量时返回参数错误
+        match (options.quality_min, options.quality_max) {
+            (Some(quality_min), Some(quality_max)) => attr.set_quality(quality_min, quality_max)?,
+            (Some(quality_min), None) => attr.set_quality(quality_min, def_quality_max)?,
+            (None, Some(quality_max)) => attr.set_quality(0, quality_max)?,
+            (None, None) => attr.set_quality(0, def_quality_max)?,
+        }
+        Ok(attr)
+    }
+
+    /// 使用固定调色板创建直方图，调色板的所有颜色都作为固定颜色，
+    /// 相同的固定调色板生成的调色板颜色和顺序都一致
+    fn fixed_palette_histogram(
+        attr: &imagequant::Attributes,
+        palette: &[imagequant::RGBA],
+    ) -> Result<Histogram, imagequant::Error> {
+        let mut histogram = imagequant::Histogram::new(attr);
+        for color in palette.iter() {
+            histogram.add_fixed_color(*color, 0.0)?;
         }
+        Ok(histogram)
+    }
+
+    /// 创建用于生成调色板的直方图，设置了固定调色板时直接使用固定调色板，
+    /// 否则加入必须保留在调色板中的固定颜色
+    pub fn histogram(
+        attr: &imagequant::Attributes,
+        options: &Options,
+    ) -> Result<Histogram, imagequant::Error> {
+        if let Some(palette) = &options.palette {
+            return Pngquant::fixed_palette_histogram(attr, palette);
+        }
+        let mut histogram = imagequant::Histogram::new(attr);
+        for color in options.fixed_colors.iter() {
+            histogram.add_fixed_color(*color, 0.0)?;
+        }
+        Ok(histogram)
+    }
+
+    /// 读取图像同级的重要性图，灰度值作为每个像素的权重，尺寸与图像不一致时忽略
+    ///
+    /// 重要性图无法使用时返回提示，由调用者通过进度回调发送到主线程输出
+    fn importance_map(path: &Path, width: u32, height: u32) -> Result<Option<Vec<u8>>, String> {
+        let map_path = path.with_extension(IMPORTANCE_MAP_EXTENSION);
+        if !map_path.is_file() {
+            return Ok(None);
+        }
+        let message = match read_rgba_png(&map_path) {
+            Ok((map_width, map_height, data)) if (map_width, map_height) == (width, height) => {
+                // 按亮度转换为灰度，完全透明的区域权重为0
+                let map = data
+                    .chunks_exact(4)
+                    .map(|p| {
+                        let luma =
+                            (p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114) / 1000;
+                        (luma * p[3] as u32 / 255) as u8
+                    })
+                    .collect();
+                return Ok(Some(map));
+            }
+            Ok(_) => Error::ImportanceMapSizeMismatch.to_string(),
+            Err(err) => err.to_string(),
+        };
+        Err(format!(
+            "ignore importance map: {} {}",
+            map_path.display().to_string().yellow(),
+            message.red()
+        ))
+    }
 
-        Pngquant {
+    /// 通过进度回调发送提示，多个工作线程的提示由主线程依次输出
+    fn send_warning(progress_sender: &ProgressSender, id: usize, warning: String) {
+        progress_sender(Progress {
             id,
-            path,
-            reader,
-            bytes: None,
-            frames: Some(frames),
-            histogram: Some(histogram),
-            imagequant_attr: Some(attr),
-            def_quality_max,
-            dithering_level,
-            progress_sender,
-            original_size: Some(original_size),
-            size: None,
+            value: 0.0,
+            warning: Some(warning),
+        });
+    }
+
+    /// 截取重要性图中apng帧区域的部分，`width`和`height`为画布尺寸
+    ///
+    /// 帧区域超出画布的部分权重为0，不会越界读取重要性图
+    fn crop_importance_map(map: &[u8], width: u32, height: u32, frame: &Frame) -> Vec<u8> {
+        let (width, height) = (width as usize, height as usize);
+        let (x_offset, y_offset) = (frame.x_offset as usize, frame.y_offset as usize);
+        let frame_width = frame.width as usize;
+        let mut cropped = vec![0; frame_width * frame.height as usize];
+        if x_offset >= width || map.len() < width * height {
+            return cropped;
+        }
+        let columns = frame_width.min(width - x_offset);
+        for (row, y) in (y_offset..height).take(frame.height as usize).enumerate() {
+            let start = y * width + x_offset;
+            cropped[row * frame_width..row * frame_width + columns]
+                .copy_from_slice(&map[start..start + columns]);
         }
+        cropped
     }
 
-    // 编码png
-    pub fn encoder(
-        &mut self,
+    /// 将png或apng的所有图像数据加入直方图，用于为多个文件生成共享调色板
+    ///
+    /// 与优化时一样只支持rgba模式的图像
+    pub fn add_to_histogram(
         path: &Path,
-        speed: Option<u8>,
-        quality_min: Option<u8>,
-        quality_max: Option<u8>,
-        compression: Compression,
+        attr: &imagequant::Attributes,
+        histogram: &mut Histogram,
+    ) -> Result<(), Error> {
+        let file = File::open(path).map_err(Error::io(path))?;
+        let mut reader = Decoder::new(file)
+            .read_info()
+            .map_err(Error::decode(path))?;
+        let info = reader.info();
+        if info.color_type != ColorType::Rgba {
+            return Err(Error::UnsupportedColorMode);
+        }
+
+        let (width, height) = (info.width, info.height);
+        let frames = if info.is_animated() {
+            Frame::read_frames(&mut reader).map_err(Error::decode(path))?
+        } else {
+            let mut buf = vec![0; reader.output_buffer_size()];
+            let output_info = reader.next_frame(&mut buf).map_err(Error::decode(path))?;
+            buf.truncate(output_info.buffer_size());
+            vec![Frame::new(
+                buf,
+                width,
+                height,
+                0,
+                0,
+                0,
+                1,
+                DisposeOp::None,
+                BlendOp::Source,
+            )]
+        };
+        // 无法使用的重要性图在优化这个文件时提示
+        let importance_map = Pngquant::importance_map(path, width, height).ok().flatten();
+        Pngquant::add_frames(attr, histogram, &frames, width, height, &importance_map)
+            .map_err(Error::quantization(path))
+    }
+
+    /// `interrupted`记录了中断或者超过文件的截止时间时返回`Break`，中止imagequant正在执行的操作
+    fn control_flow(
+        interrupted: &AtomicBool,
+        deadline: Option<Instant>,
+    ) -> imagequant::ControlFlow {
+        let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if interrupted.load(Ordering::Relaxed) || timed_out {
+            imagequant::ControlFlow::Break
+        } else {
+            imagequant::ControlFlow::Continue
+        }
+    }
+
+    /// 写入文件之前检查中断信号和截止时间，最后一次进度回调之后的抖动和编码期间
+    /// 收到的中断或者超时在这里中止，原文件保持不变
+    fn check_abort(&self) -> Result<(), Error> {
+        if interrupt::is_interrupted() {
+            return Err(Error::Interrupted);
+        }
+        if self.options.timed_out() {
+            return Err(Error::TimedOut);
+        }
+        Ok(())
+    }
+
+    /// 在量化结果上设置中止的回调，`interrupted`记录了中断或者超过截止时间时中止重新映射和抖动，
+    /// 优化时传入`interrupt::flag()`
+    ///
+    /// 重新映射的进度不发送到主线程，避免进度在调色板生成完成后回退
+    fn set_abort_callback(
+        res: &mut imagequant::QuantizationResult,
+        interrupted: &'static AtomicBool,
+        deadline: Option<Instant>,
     ) {
+        res.set_progress_callback(move |_| Pngquant::control_flow(interrupted, deadline));
+    }
+
+    /// 将所有帧的图像数据加入直方图，`width`和`height`为画布尺寸，用于截取每帧区域的重要性图
+    fn add_frames(
+        attr: &imagequant::Attributes,
+        histogram: &mut Histogram,
+        frames: &[Frame],
+        width: u32,
+        height: u32,
+        importance_map: &Option<Vec<u8>>,
+    ) -> Result<(), imagequant::Error> {
+        for frame in frames.iter() {
+            let pixels = rgb::FromSlice::as_rgba(&frame.data[..]);
+            let mut image = imagequant::Image::new_borrowed(
+                attr,
+                pixels,
+                frame.width as usize,
+                frame.height as usize,
+                0.0,
+            )?;
+            if let Some(map) = importance_map {
+                image
+                    .set_importance_map(Pngquant::crop_importance_map(map, width, height, frame))?;
+            }
+            // 保存图像直方图，用于稍后的调色板生成
+            histogram.add_image(attr, &mut image)?;
+        }
+        Ok(())
+    }
+
+    // 编码png
+    pub fn encoder(&mut self, path: &Path) -> Result<(), Error> {
         // 是否是apng根据类型执行不同的逻辑
-        if let Some(bytes) = &self.bytes {
-            let bytes = bytes.to_vec();
-            self.encoder_png(bytes, path, speed, quality_min, quality_max, compression)
+        if self.bytes.is_some() {
+            self.encoder_png(path)?;
         }
-        if let Some(_) = &self.frames {
-            self.encoder_apng(path, compression)
+        if self.frames.is_some() {
+            self.encoder_apng(path)?;
         }
+        Ok(())
     }
 
-    // 编码apng
-    fn encoder_apng(&mut self, path: &Path, compression: Compression) {
-        // apng对象数据
-        if let (Some(histogram), Some(attr), Some(frames)) = (
-            self.histogram.as_mut(),
-            &self.imagequant_attr,
-            self.frames.as_mut(),
-        ) {
-            // 为添加到直方图的所有图像/颜色生成调色板。
-            let mut res = histogram.quantize(&attr).unwrap();
-            // 设置平滑图像参数
-            res.set_dithering_level(self.dithering_level.unwrap())
-                .unwrap();
-            // 用于保存调色板
-            let mut histogram_palette: Vec<imagequant::RGBA> = vec![];
-
-            // 读取每帧数据，将图像重新映射到调色板+索引中
-            for frame in frames.iter_mut() {
-                let pixels = rgb::FromSlice::as_rgba(&frame.data[..]);
-                let mut image = imagequant::Image::new_borrowed(
-                    &attr,
-                    pixels,
-                    frame.width as usize,
-                    frame.height as usize,
-                    0.0,
-                )
-                .unwrap();
-                // 生成调色板和索引
-                let (palette, pixels) = res.remapped(&mut image).unwrap();
-
-                // 因为是共享调色板，保存一次就行了
-                if histogram_palette.len() == 0 {
-                    histogram_palette = palette;
-                }
-                // 保存索引数据
-                frame.pixels = Some(pixels);
+    /// 二分查找编码后不超过`max_bytes`的最高质量，最低质量也超出限制时依次提高速度，
+    /// 最快速度也超出限制时再减少调色板颜色数量
+    ///
+    /// `encode`返回使用指定参数的编码结果，量化失败时返回`None`；
+    /// 返回满足限制的优化参数和对应的编码结果，不需要再次编码，都无法满足时返回`None`
+    fn search_options<F>(
+        options: &Options,
+        max_bytes: u64,
+        mut encode: F,
+    ) -> Option<(Options, Encoded)>
+    where
+        F: FnMut(&Options) -> Option<Encoded>,
+    {
+        let mut fits = |options: &Options| {
+            encode(options).filter(|encoded| encoded.data.len() as u64 <= max_bytes)
+        };
+        let mut options = options.clone();
+        let mut best = None;
+
+        let quality_min = options.quality_min.unwrap_or(0);
+        let (mut low, mut high) = (quality_min, options.quality_max.unwrap_or(100));
+        while low <= high {
+            let quality = low + (high - low) / 2;
+            options.quality_max = Some(quality);
+            if let Some(encoded) = fits(&options) {
+                best = Some((options.clone(), encoded));
+                low = quality + 1;
+            } else if quality == 0 {
+                break;
+            } else {
+                high = quality - 1;
             }
+        }
+        if best.is_some() {
+            return best;
+        }
 
-            // 调色板数据格式转换为png规范
-            let mut rbg_palette: Vec<u8> = Vec::new();
-            let mut trns: Vec<u8> = Vec::new();
+        // 最低质量也超出限制，速度越快调色板越粗糙，编码后通常更小，选择满足限制的最慢速度
+        options.quality_max = Some(quality_min);
+        let speed = options.speed.unwrap_or(DEF_SPEED);
+        for faster in speed + 1..=10 {
+            options.speed = Some(faster);
+            if let Some(encoded) = fits(&options) {
+                return Some((options, encoded));
+            }
+        }
 
-            for f in histogram_palette.iter() {
-                rbg_palette.push(f.r);
-                rbg_palette.push(f.g);
-                rbg_palette.push(f.b);
-                trns.push(f.a);
+        // 最快速度也超出限制，使用原来的速度减少调色板颜色数量
+        options.speed = Some(speed);
+        let (mut low, mut high) = (2, options.colors.unwrap_or(256));
+        while low <= high {
+            let colors = low + (high - low) / 2;
+            options.colors = Some(colors);
+            if let Some(encoded) = fits(&options) {
+                best = Some((options.clone(), encoded));
+                low = colors + 1;
+            } else {
+                high = colors - 1;
             }
+        }
+        best
+    }
 
-            // 下面开始写入覆盖原png图像
-            let info = self.reader.info();
-            let file = File::create(path).unwrap();
-            let ref mut w = BufWriter::new(file);
-
-            let mut encoder = png::Encoder::new(w, info.width, info.height); // Width is 2 pixels and height is 1.
-            encoder.set_depth(png::BitDepth::Eight);
-            encoder.set_compression(compression);
-            encoder.set_color(png::ColorType::Indexed);
-            encoder.set_trns(trns);
-            encoder.set_palette(rbg_palette);
-
-            if let Some(animation) = info.animation_control {
-                let id = self.id;
-                encoder
-                    .set_animated(animation.num_frames, animation.num_plays)
-                    .unwrap();
-                let mut writer = encoder.write_header().unwrap();
+    /// 将调色板和索引数据编码为png，`frames`为空时编码为普通png，否则编码为apng
+    fn encode_indexed(
+        &self,
+        palette: &[imagequant::RGBA],
+        pixels: &[u8],
+        frames: &[Frame],
+        compression: png::Compression,
+    ) -> Result<Vec<u8>, png::EncodingError> {
+        // 调色板数据格式转换为png规范
+        let mut rbg_palette: Vec<u8> = Vec::new();
+        let mut trns: Vec<u8> = Vec::new();
+
+        for f in palette.iter() {
+            rbg_palette.push(f.r);
+            rbg_palette.push(f.g);
+            rbg_palette.push(f.b);
+            trns.push(f.a);
+        }
+
+        let mut data = Vec::new();
+        // 颜色较少时使用更低的位深
+        let bit_depth = indexed_bit_depth(palette.len());
+        let mut encoder = png::Encoder::new(&mut data, self.width, self.height);
+        encoder.set_depth(bit_depth);
+        encoder.set_compression(compression);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_trns(trns);
+        encoder.set_palette(rbg_palette);
+
+        match self.animation_control {
+            Some(animation) if !frames.is_empty() => {
+                encoder.set_animated(animation.num_frames, animation.num_plays)?;
+                let mut writer = encoder.write_header()?;
 
                 // 每帧写入
                 for frame in frames.iter() {
                     if let Some(pixels) = &frame.pixels {
-                        writer.reset_frame_position().unwrap();
-                        writer
-                            .set_frame_dimension(frame.width, frame.height)
-                            .unwrap();
-                        writer
-                            .set_frame_position(frame.x_offset, frame.y_offset)
-                            .unwrap();
-                        writer
-                            .set_frame_delay(frame.delay_num, frame.delay_den)
-                            .unwrap();
-                        writer.set_blend_op(frame.blend_op).unwrap();
-                        writer.set_dispose_op(frame.dispose_op).unwrap();
-                        writer.write_image_data(&pixels).unwrap(); // Save
+                        writer.reset_frame_position()?;
+                        writer.set_frame_dimension(frame.width, frame.height)?;
+                        writer.set_frame_position(frame.x_offset, frame.y_offset)?;
+                        writer.set_frame_delay(frame.delay_num, frame.delay_den)?;
+                        writer.set_blend_op(frame.blend_op)?;
+                        writer.set_dispose_op(frame.dispose_op)?;
+                        writer.write_image_data(&pack_indices(pixels, frame.width, bit_depth))?;
+                        // Save
                     }
                 }
+                writer.finish()?;
+            }
+            _ => {
+                let mut writer = encoder.write_header()?;
+                writer.write_image_data(&pack_indices(pixels, self.width, bit_depth))?; // Save
+                writer.finish()?;
+            }
+        }
+        Ok(data)
+    }
 
-                // 结束工作发送总进度
-                let progress_sender = self.progress_sender.clone();
-                progress_sender
-                    .send(Progress { id, value: PROGRESS_CONSTANT })
-                    .unwrap();
+    /// 使用不同的最大颜色数量和最高质量编码但不写入文件，返回每组参数编码后的大小和总误差，
+    /// 用于在多个文件之间分配文件大小预算
+    ///
+    /// `settings`为最大颜色数量和最高质量，最高质量为`None`时使用设置的最高质量；
+    /// 收到中断信号或者超过截止时间时不再试编码剩下的参数
+    pub fn probe(&mut self, settings: &[(u32, Option<u8>)]) -> Vec<Probe> {
+        let mut options = self.options.clone();
+        let bytes = self.bytes.take();
+        // 均方误差乘以像素数量，大图像的误差按像素数量计入总误差
+        let pixels: u64 = match &self.frames {
+            Some(frames) => frames
+                .iter()
+                .map(|frame| frame.width as u64 * frame.height as u64)
+                .sum(),
+            None => self.width as u64 * self.height as u64,
+        };
 
-                // 记录压缩后的文件大小
-                let file = File::open(path).unwrap();
-                let size = file.metadata().unwrap().len();
-                self.set_size(size);
+        let mut points = Vec::with_capacity(settings.len());
+        for &(colors, quality_max) in settings.iter() {
+            options.colors = Some(colors);
+            options.quality_max = quality_max.or(self.options.quality_max);
+            let encoded = match &bytes {
+                Some(bytes) => self.encode_png(bytes, &options),
+                None => self.encode_apng(&options),
+            };
+            match encoded {
+                Ok(encoded) => points.push(Probe {
+                    colors,
+                    quality_max,
+                    size: encoded.data.len() as u64,
+                    error: encoded.error.unwrap_or(0.0) * pixels as f64,
+                }),
+                Err(Error::Interrupted | Error::TimedOut) => break,
+                // 无法满足最低质量等量化失败的参数跳过
+                Err(_) => {}
             }
         }
+        self.bytes = bytes;
+        points
     }
 
-    fn encoder_png(
-        &mut self,
-        bytes: Vec<imagequant::RGBA>,
-        path: &Path,
-        speed: Option<u8>,
-        quality_min: Option<u8>,
-        quality_max: Option<u8>,
-        compression: Compression,
-    ) {
-        let info = self.reader.info();
-        let mut attr = imagequant::new();
-        let progress_sender = self.progress_sender.clone();
-        let id = self.id;
+    /// 使用`options`为所有帧生成共享调色板并编码apng，返回编码后的数据和调色板
+    fn encode_apng(&mut self, options: &Options) -> Result<Encoded, Error> {
+        let path = self.path;
+        let quantization = |err| Error::quantization(path)(err);
+        let attr = Pngquant::attributes(
+            self.id,
+            options,
+            self.def_quality_max,
+            self.progress_sender.clone(),
+        )
+        .map_err(quantization)?;
+        let (Some(histogram), Some(frames)) = (self.histogram.as_mut(), self.frames.as_mut())
+        else {
+            return Ok(Encoded::default());
+        };
 
-        // 调色板生成进度更新回调
-        attr.set_progress_callback(move |progress| {
-            // 将进度发送到主线程
-            progress_sender
-                .send(Progress {
-                    id,
-                    value: progress,
-                })
-                .unwrap();
-            imagequant::ControlFlow::Continue
-        });
+        // 为添加到直方图的所有图像/颜色生成调色板。
+        let mut res = histogram.quantize(&attr).map_err(quantization)?;
+        Pngquant::set_abort_callback(&mut res, interrupt::flag(), options.deadline);
+        // 所有帧共享调色板，使用调色板的量化误差和质量
+        let error = res.quantization_error();
+        let quality = res.quantization_quality();
+        // 设置平滑图像参数
+        res.set_dithering_level(options.dithering_level.unwrap_or(1.0))
+            .map_err(quantization)?;
+        // 用于保存调色板
+        let mut histogram_palette: Vec<imagequant::RGBA> = vec![];
 
-        // 设置压缩算法执行速度
-        if let Some(speed) = speed {
-            attr.set_speed(speed as i32).unwrap();
+        // 读取每帧数据，将图像重新映射到调色板+索引中
+        for frame in frames.iter_mut() {
+            let pixels = rgb::FromSlice::as_rgba(&frame.data[..]);
+            let mut image = imagequant::Image::new_borrowed(
+                &attr,
+                pixels,
+                frame.width as usize,
+                frame.height as usize,
+                0.0,
+            )
+            .map_err(quantization)?;
+            // 生成调色板和索引
+            let (palette, pixels) = res.remapped(&mut image).map_err(quantization)?;
+
+            // 因为是共享调色板，保存一次就行了
+            if histogram_palette.is_empty() {
+                histogram_palette = palette;
+            }
+            // 保存索引数据
+            frame.pixels = Some(pixels);
         }
-        match (quality_min, quality_max) {
-            (Some(quality_min), Some(quality_max)) => {
-                attr.set_quality(quality_min, quality_max).unwrap()
+
+        let frames = self.frames.as_deref().unwrap_or_default();
+        let data = self
+            .encode_indexed(&histogram_palette, &[], frames, options.compression)
+            .map_err(Error::encode(path))?;
+        // 索引数据随编码结果保存，查找优化参数时下一次编码不会覆盖
+        let frame_pixels = self
+            .frames
+            .iter_mut()
+            .flatten()
+            .map(|frame| frame.pixels.take())
+            .collect();
+        Ok(Encoded {
+            data,
+            palette: histogram_palette,
+            error,
+            quality,
+            frame_pixels,
+        })
+    }
+
+    // 编码apng
+    fn encoder_apng(&mut self, path: &Path) -> Result<(), Error> {
+        // 设置了文件大小限制时查找满足限制的优化参数，直接使用查找时的编码结果
+        let options = self.options.clone();
+        let encoded = match options.max_bytes {
+            Some(max_bytes) => {
+                Pngquant::search_options(&options, max_bytes, |options| {
+                    self.encode_apng(options).ok()
+                })
+                .ok_or_else(|| self.over_budget())?
+                .1
             }
-            (Some(quality_min), None) => {
-                attr.set_quality(quality_min, self.def_quality_max).unwrap()
+            None => self.encode_apng(&options)?,
+        };
+        let Encoded {
+            data,
+            palette: histogram_palette,
+            error,
+            quality,
+            frame_pixels,
+        } = encoded;
+        self.mse = error;
+        self.quality = quality;
+        for (frame, pixels) in self.frames.iter_mut().flatten().zip(frame_pixels) {
+            frame.pixels = pixels;
+        }
+
+        // 下面开始写入覆盖原png图像
+        self.check_abort()?;
+        let original = self.backup(path);
+        Pngquant::write_atomic(path, &data).map_err(Error::io(path))?;
+        // 误差图在检查之前写入，被拒绝的文件也能查看误差
+        let diff = self.write_diff(path);
+        self.verify(path, original)?;
+
+        // 优化后的文件已经写入，附加输出失败时只提示，不作为文件失败
+        let mut outputs = vec![diff];
+        if let (Some(animation), Some(frames)) = (self.animation_control, &self.frames) {
+            // 同时导出gif动画
+            if let Some(alpha_threshold) = self.options.gif {
+                let gif_path = path.with_extension("gif");
+                outputs.push(
+                    write_gif(
+                        &gif_path,
+                        self.width,
+                        self.height,
+                        &histogram_palette,
+                        frames,
+                        animation.num_plays,
+                        alpha_threshold,
+                    )
+                    .map_err(Error::io(&gif_path)),
+                );
             }
-            (None, Some(quality_max)) => attr.set_quality(0, quality_max).unwrap(),
-            (None, None) => attr.set_quality(0, self.def_quality_max).unwrap(),
         }
+        outputs.push(self.export_palette(path, &histogram_palette));
+        self.warn_outputs(outputs);
+
+        // 结束工作发送总进度
+        self.send_progress(PROGRESS_CONSTANT);
+
+        // 记录压缩后的文件大小
+        self.set_size(data.len() as u64);
+        Ok(())
+    }
+
+    /// 使用`options`生成调色板并编码png，返回编码后的数据和调色板
+    fn encode_png(&self, bytes: &[imagequant::RGBA], options: &Options) -> Result<Encoded, Error> {
+        let quantization = |err| Error::quantization(self.path)(err);
+        let attr = Pngquant::attributes(
+            self.id,
+            options,
+            self.def_quality_max,
+            self.progress_sender.clone(),
+        )
+        .map_err(quantization)?;
 
         // 描述位图
         let mut img = attr
-            .new_image(&bytes[..], info.width as usize, info.height as usize, 0.0)
-            .unwrap();
+            .new_image(bytes, self.width as usize, self.height as usize, 0.0)
+            .map_err(quantization)?;
+        // 重要性图中权重高的区域分配更多的调色板颜色
+        if let Some(map) = &self.importance_map {
+            img.set_importance_map(&map[..]).map_err(quantization)?;
+        }
 
-        // 生成调色板
-        let mut res = match attr.quantize(&mut img) {
-            Ok(res) => res,
-            Err(err) => panic!("Quantization failed, because: {:?}", err),
+        // 生成调色板，设置了固定调色板时直接使用固定调色板
+        let res = match &options.palette {
+            Some(palette) => Pngquant::fixed_palette_histogram(&attr, palette)
+                .and_then(|mut histogram| histogram.quantize(&attr)),
+            None => {
+                // 必须保留在调色板中的固定颜色
+                for color in options.fixed_colors.iter() {
+                    img.add_fixed_color(*color).map_err(quantization)?;
+                }
+                attr.quantize(&mut img)
+            }
         };
+        let mut res = res.map_err(quantization)?;
+        Pngquant::set_abort_callback(&mut res, interrupt::flag(), options.deadline);
 
         // Enable dithering for subsequent remappings
-        res.set_dithering_level(self.dithering_level.unwrap())
-            .unwrap();
+        res.set_dithering_level(options.dithering_level.unwrap_or(1.0))
+            .map_err(quantization)?;
 
         // You can reuse the result to generate several images with the same palette
-        let (palette, pixels) = res.remapped(&mut img).unwrap();
-
-        let mut rbg_palette: Vec<u8> = Vec::new();
-        let mut trns: Vec<u8> = Vec::new();
+        let (palette, pixels) = res.remapped(&mut img).map_err(quantization)?;
+        // 重新映射后的误差和质量更准确
+        let error = res.remapping_error();
+        let quality = res.remapping_quality();
 
-        for f in palette.iter() {
-            rbg_palette.push(f.r);
-            rbg_palette.push(f.g);
-            rbg_palette.push(f.b);
-            trns.push(f.a);
-        }
+        let data = self
+            .encode_indexed(&palette, &pixels, &[], options.compression)
+            .map_err(Error::encode(self.path))?;
+        Ok(Encoded {
+            data,
+            palette,
+            error,
+            quality,
+            frame_pixels: Vec::new(),
+        })
+    }
 
-        let file = File::create(path).unwrap();
-        let ref mut w = BufWriter::new(file);
+    fn encoder_png(&mut self, path: &Path) -> Result<(), Error> {
+        let bytes = self.bytes.as_deref().unwrap_or_default();
+        // 设置了文件大小限制时查找满足限制的优化参数，直接使用查找时的编码结果
+        let encoded = match self.options.max_bytes {
+            Some(max_bytes) => {
+                Pngquant::search_options(&self.options, max_bytes, |options| {
+                    self.encode_png(bytes, options).ok()
+                })
+                .ok_or_else(|| self.over_budget())?
+                .1
+            }
+            None => self.encode_png(bytes, &self.options)?,
+        };
+        let Encoded {
+            data,
+            palette,
+            error,
+            quality,
+            ..
+        } = encoded;
+        self.mse = error;
+        self.quality = quality;
 
-        let mut encoder = png::Encoder::new(w, info.width, info.height); // Width is 2 pixels and height is 1.
-        encoder.set_depth(png::BitDepth::Eight);
-        encoder.set_compression(compression);
-        encoder.set_color(png::ColorType::Indexed);
-        encoder.set_trns(trns);
-        encoder.set_palette(rbg_palette);
+        self.check_abort()?;
+        let original = self.backup(path);
+        Pngquant::write_atomic(path, &data).map_err(Error::io(path))?;
+        // 误差图在检查之前写入，被拒绝的文件也能查看误差
+        let diff = self.write_diff(path);
+        self.verify(path, original)?;
 
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(&pixels).unwrap(); // Save
-        let progress_sender = self.progress_sender.clone();
+        // 优化后的文件已经写入，附加输出失败时只提示，不作为文件失败
+        let outputs = vec![diff, self.export_palette(path, &palette)];
+        self.warn_outputs(outputs);
         // 结束工作发送总进度
-        progress_sender
-            .send(Progress { id, value: PROGRESS_CONSTANT })
-            .unwrap();
+        self.send_progress(PROGRESS_CONSTANT);
 
         // 记录压缩后的文件大小
-        let file = File::open(path).unwrap();
-        let size = file.metadata().unwrap().len();
-        self.set_size(size);
+        self.set_size(data.len() as u64);
+        Ok(())
+    }
+
+    /// 没有找到满足文件大小限制的优化参数，查找时收到中断信号的为中断，超过截止时间的为超时
+    fn over_budget(&self) -> Error {
+        if interrupt::is_interrupted() {
+            Error::Interrupted
+        } else if self.options.timed_out() {
+            Error::TimedOut
+        } else {
+            Error::OverBudget
+        }
+    }
+
+    /// 写入文件，先写入同一文件夹的临时文件再重命名覆盖，进程在写入时结束也不会留下不完整的文件
+    fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = Path::new(&temp);
+        fs::write(temp, data)
+            .and_then(|()| fs::rename(temp, path))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(temp);
+            })
+    }
+
+    /// 需要校验结构相似度时，保存写入前的文件用于恢复
+    fn backup(&self, path: &Path) -> Option<Vec<u8>> {
+        self.options.min_ssim.and_then(|_| fs::read(path).ok())
+    }
+
+    /// 解码写入的文件与源图像比较结构相似度，低于阈值时恢复写入前的文件，
+    /// 写入前文件不存在时删除写入的文件
+    fn verify(&mut self, path: &Path, original: Option<Vec<u8>>) -> Result<(), Error> {
+        let Some(min_ssim) = self.options.min_ssim else {
+            return Ok(());
+        };
+        // 写入的文件无法解码时相似度为0
+        let similarity = self.similarity(path).unwrap_or(0.0);
+        self.ssim = Some(similarity);
+        if similarity >= min_ssim {
+            return Ok(());
+        }
+
+        match original {
+            Some(original) => Pngquant::write_atomic(path, &original),
+            None => fs::remove_file(path),
+        }
+        .map_err(Error::io(path))?;
+        Err(Error::SimilarityTooLow)
+    }
+
+    /// 计算写入的文件与源图像的结构相似度，apng逐帧合成后比较，取所有帧中最低的值
+    fn similarity(&self, path: &Path) -> io::Result<f64> {
+        if let Some(bytes) = &self.bytes {
+            let (_, _, data) = read_rgba_png(path)?;
+            let source = rgb::ComponentBytes::as_bytes(&bytes[..]);
+            return Ok(ssim(self.width, self.height, source, &data));
+        }
+
+        let (_, _, frames) = read_rgba_frames(path)?;
+        let source_frames = self.frames.as_deref().unwrap_or_default();
+        let mut source_canvas = Canvas::new(self.width, self.height);
+        let mut canvas = Canvas::new(self.width, self.height);
+        let mut similarity: f64 = 1.0;
+        for (source, frame) in source_frames.iter().zip(frames.iter()) {
+            let source = source_canvas.render(source);
+            let data = canvas.render(frame);
+            similarity = similarity.min(ssim(self.width, self.height, &source, &data));
+        }
+        Ok(similarity)
+    }
+
+    /// 在对比文件夹中写入源图像与写入的文件的误差热力图，以及可选的左右对比图
+    ///
+    /// apng逐帧合成后比较，热力图为每个像素在所有帧中的最大误差，对比图使用总误差最大的帧
+    fn write_diff(&self, path: &Path) -> Result<(), Error> {
+        let Some(diff_dir) = &self.options.diff_dir else {
+            return Ok(());
+        };
+        let (source, result, errors) = if let Some(bytes) = &self.bytes {
+            let (_, _, data) = read_rgba_png(path).map_err(Error::io(path))?;
+            let source = rgb::ComponentBytes::as_bytes(&bytes[..]).to_vec();
+            let errors = pixel_errors(&source, &data);
+            (source, data, errors)
+        } else {
+            let (_, _, frames) = read_rgba_frames(path).map_err(Error::io(path))?;
+            let source_frames = self.frames.as_deref().unwrap_or_default();
+            let mut source_canvas = Canvas::new(self.width, self.height);
+            let mut canvas = Canvas::new(self.width, self.height);
+            let mut errors = vec![0.0; self.width as usize * self.height as usize];
+            // 总误差最大的帧
+            let mut worst = (-1.0, vec![], vec![]);
+            for (source, frame) in source_frames.iter().zip(frames.iter()) {
+                let source = source_canvas.render(source);
+                let data = canvas.render(frame);
+                let frame_errors = pixel_errors(&source, &data);
+                for (error, frame_error) in errors.iter_mut().zip(frame_errors.iter()) {
+                    *error = frame_error.max(*error);
+                }
+                let total: f32 = frame_errors.iter().sum();
+                if total > worst.0 {
+                    worst = (total, source, data);
+                }
+            }
+            (worst.1, worst.2, errors)
+        };
+
+        fs::create_dir_all(diff_dir).map_err(Error::io(diff_dir))?;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let heatmap_path = diff_dir.join(format!("{}.heatmap.png", stem));
+        write_rgba_png(
+            &heatmap_path,
+            self.width,
+            self.height,
+            &heatmap(&errors),
+            self.options.compression,
+        )
+        .map_err(Error::io(&heatmap_path))?;
+        if self.options.side_by_side && !source.is_empty() {
+            let compare_path = diff_dir.join(format!("{}.compare.png", stem));
+            write_rgba_png(
+                &compare_path,
+                self.width * 2,
+                self.height,
+                &side_by_side(self.width, self.height, &source, &result),
+                self.options.compression,
+            )
+            .map_err(Error::io(&compare_path))?;
+        }
+        Ok(())
+    }
+
+    /// 同时导出调色板文件
+    fn export_palette(&self, path: &Path, palette: &[imagequant::RGBA]) -> Result<(), Error> {
+        let Some(format) = self.options.export_palette else {
+            return Ok(());
+        };
+        let palette_path = format.export_path(path);
+        write_palette(&palette_path, format, palette).map_err(Error::io(&palette_path))
+    }
+
+    /// 误差图、gif动画和调色板文件写入失败时提示，优化后的文件保留并正常记录
+    fn warn_outputs(&self, outputs: Vec<Result<(), Error>>) {
+        for err in outputs.into_iter().filter_map(Result::err) {
+            Pngquant::send_warning(
+                &self.progress_sender,
+                self.id,
+                format!("side output failed: {}", err.to_string().red()),
+            );
+        }
+    }
+
+    /// 向主线程发送进度
+    fn send_progress(&self, value: f32) {
+        (self.progress_sender)(Progress {
+            id: self.id,
+            value,
+            warning: None,
+        });
     }
 
     /// 记录压缩后的文件大小
@@ -435,3 +1173,159 @@ impl<'a> Pngquant<'a> {
         self.size = Some(size);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn frame(width: u32, height: u32, x_offset: u32, y_offset: u32) -> Frame {
+        Frame::new(
+            Vec::new(),
+            width,
+            height,
+            x_offset,
+            y_offset,
+            1,
+            10,
+            DisposeOp::None,
+            BlendOp::Source,
+        )
+    }
+
+    /// 使用`size`计算编码大小的假编码，记录每次编码使用的参数
+    fn search(
+        options: &Options,
+        max_bytes: u64,
+        size: impl Fn(&Options) -> Option<usize>,
+    ) -> (Option<(Options, Encoded)>, Vec<Options>) {
+        let mut tried = vec![];
+        let result = Pngquant::search_options(options, max_bytes, |options| {
+            tried.push(options.clone());
+            size(options).map(|size| Encoded {
+                data: vec![0; size],
+                quality: options.quality_max,
+                ..Encoded::default()
+            })
+        });
+        (result, tried)
+    }
+
+    #[test]
+    fn search_highest_quality() {
+        let (result, tried) = search(&Options::default(), 500, |options| {
+            Some(options.quality_max.unwrap() as usize * 10)
+        });
+        let (options, encoded) = result.unwrap();
+        assert_eq!(options.quality_max, Some(50));
+        // 返回最高质量的编码结果，不是最后一次编码的结果
+        assert_eq!(encoded.quality, Some(50));
+        assert_eq!(encoded.data.len(), 500);
+        assert!(tried.len() <= 8);
+        assert!(tried.iter().all(|options| options.colors.is_none()));
+    }
+
+    #[test]
+    fn search_faster_speed() {
+        let options = Options {
+            quality_min: Some(30),
+            ..Options::default()
+        };
+        let (result, tried) = search(&options, 620, |options| {
+            Some(1000 - options.speed.unwrap_or(DEF_SPEED) as usize * 50)
+        });
+        let (options, encoded) = result.unwrap();
+        assert_eq!(options.speed, Some(8));
+        assert_eq!(options.quality_max, Some(30));
+        assert_eq!(encoded.data.len(), 600);
+        assert_eq!(tried.last().unwrap().speed, Some(8));
+    }
+
+    #[test]
+    fn search_fewer_colors() {
+        let options = Options {
+            speed: Some(3),
+            ..Options::default()
+        };
+        let (result, _) = search(&options, 1000, |options| {
+            Some(options.colors.unwrap_or(256) as usize * 10)
+        });
+        let (options, encoded) = result.unwrap();
+        assert_eq!(options.colors, Some(100));
+        // 减少颜色数量时恢复原来的速度
+        assert_eq!(options.speed, Some(3));
+        assert_eq!(options.quality_max, Some(0));
+        assert_eq!(encoded.data.len(), 1000);
+    }
+
+    #[test]
+    fn search_over_budget() {
+        let (result, tried) = search(&Options::default(), 10, |_| Some(100));
+        assert!(result.is_none());
+        assert!(tried.iter().any(|options| options.colors == Some(2)));
+
+        // 量化失败的参数按不满足限制处理
+        let (result, _) = search(&Options::default(), 10, |_| None);
+        assert!(result.is_none());
+    }
+
+    /// 量化一张有渐变的8x8图像，返回图像和量化结果
+    fn quantize() -> (imagequant::Image<'static>, imagequant::QuantizationResult) {
+        let attr = imagequant::new();
+        let pixels: Vec<imagequant::RGBA> = (0..64u8)
+            .map(|index| imagequant::RGBA::new(index * 4, 255 - index * 4, index, 255))
+            .collect();
+        let mut image = attr.new_image(pixels, 8, 8, 0.0).unwrap();
+        let res = attr.quantize(&mut image).unwrap();
+        (image, res)
+    }
+
+    /// 中断状态使用测试自己的状态，不影响同时运行的其他测试
+    #[test]
+    fn timed_out_and_interrupted_remap() {
+        static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+        let (mut image, mut res) = quantize();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        Pngquant::set_abort_callback(&mut res, &INTERRUPTED, Some(deadline));
+        assert!(res.remapped(&mut image).is_ok());
+
+        // 超过截止时间
+        let (mut image, mut res) = quantize();
+        Pngquant::set_abort_callback(&mut res, &INTERRUPTED, Some(Instant::now()));
+        assert!(matches!(
+            res.remapped(&mut image),
+            Err(imagequant::Error::Aborted)
+        ));
+
+        // 记录中断，与收到Ctrl-C时信号处理函数的记录一样
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        let (mut image, mut res) = quantize();
+        Pngquant::set_abort_callback(&mut res, &INTERRUPTED, None);
+        assert!(matches!(
+            res.remapped(&mut image),
+            Err(imagequant::Error::Aborted)
+        ));
+        assert!(!interrupt::is_interrupted());
+    }
+
+    #[test]
+    fn crop_importance_map_inside_canvas() {
+        // 3x2的画布
+        let map = [1, 2, 3, 4, 5, 6];
+        let cropped = Pngquant::crop_importance_map(&map, 3, 2, &frame(2, 2, 1, 0));
+        assert_eq!(cropped, [2, 3, 5, 6]);
+    }
+
+    #[test]
+    fn crop_importance_map_outside_canvas() {
+        let map = [1, 2, 3, 4, 5, 6];
+        // 超出画布的部分权重为0
+        let cropped = Pngquant::crop_importance_map(&map, 3, 2, &frame(2, 2, 2, 1));
+        assert_eq!(cropped, [6, 0, 0, 0]);
+        let cropped = Pngquant::crop_importance_map(&map, 3, 2, &frame(1, 1, 5, 5));
+        assert_eq!(cropped, [0]);
+        // 重要性图尺寸与画布不一致
+        let cropped = Pngquant::crop_importance_map(&map[..4], 3, 2, &frame(1, 1, 0, 0));
+        assert_eq!(cropped, [0]);
+    }
+}
@@ -0,0 +1,327 @@
+use png::Decoder;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 每个像素在工作任务中占用的内存估计，包括解码的rgba数据、重新映射后的索引
+/// 以及imagequant内部使用的浮点像素和噪声图
+const BYTES_PER_PIXEL: u64 = 24;
+
+/// 工作任务的资源估计，根据IHDR的尺寸和acTL的帧数计算，不需要解码图像数据
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cost {
+    /// 像素数量，apng为所有帧的像素数量
+    pub pixels: u64,
+    /// 估计占用的内存字节数
+    pub memory: u64,
+}
+
+impl Cost {
+    /// 读取png的文件头估计工作任务的资源，无法读取时返回空的估计，执行工作任务时会报告错误
+    pub fn estimate(path: &Path) -> Cost {
+        let Ok(file) = File::open(path) else {
+            return Cost::default();
+        };
+        let Ok(reader) = Decoder::new(file).read_info() else {
+            return Cost::default();
+        };
+        let info = reader.info();
+        let frames = info
+            .animation_control
+            .map_or(1, |animation| animation.num_frames.max(1));
+        let pixels = info.width as u64 * info.height as u64 * frames as u64;
+        Cost {
+            pixels,
+            memory: pixels * BYTES_PER_PIXEL,
+        }
+    }
+}
+
+/// 工作任务开始执行的顺序
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Strategy {
+    /// 按像素数量从大到小，最大的图像最先开始，不会在最后单独执行成为最慢的部分
+    #[default]
+    Largest,
+    /// 按扫描目录时发现文件的顺序
+    Directory,
+    /// 随机顺序
+    Random,
+}
+
+impl Strategy {
+    /// 返回工作任务按这个策略开始执行的下标顺序，`costs`为按工作列表顺序的资源估计
+    pub fn order(self, costs: &[Cost]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..costs.len()).collect();
+        match self {
+            Strategy::Largest => order.sort_by_key(|&index| std::cmp::Reverse(costs[index].pixels)),
+            Strategy::Directory => {}
+            Strategy::Random => {
+                // 使用当前时间作为种子的xorshift打乱顺序，不需要可重现的随机数
+                let mut seed = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |duration| duration.as_nanos() as u64)
+                    | 1;
+                for i in (1..order.len()).rev() {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    order.swap(i, (seed % (i as u64 + 1)) as usize);
+                }
+            }
+        }
+        order
+    }
+}
+
+/// 读取系统当前可用的内存字节数，不是linux系统或者无法读取时返回`None`
+pub fn available_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| {
+            value
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .map(|kb| kb * 1024)
+}
+
+/// 工作任务调度器
+///
+/// 所有工作任务共享`jobs`个线程，正在执行的工作任务估计占用的内存不超过`memory`，
+/// 剩下的工作任务少于空闲的线程时，按像素数量分配多个线程给同一个工作任务，
+/// 大图像的重新映射和抖动分到多个线程执行，避免最后只剩一个大图像单线程执行
+#[derive(Debug)]
+pub struct Scheduler {
+    /// 线程总数
+    jobs: usize,
+    /// 内存预算，`None`时不限制
+    memory: Option<u64>,
+    /// 正在执行的工作任务占用的线程数量
+    used_threads: usize,
+    /// 正在执行的工作任务估计占用的内存
+    used_memory: u64,
+}
+
+impl Scheduler {
+    pub fn new(jobs: usize, memory: Option<u64>) -> Scheduler {
+        Scheduler {
+            jobs,
+            memory,
+            used_threads: 0,
+            used_memory: 0,
+        }
+    }
+
+    /// 是否没有正在执行的工作任务
+    pub fn is_idle(&self) -> bool {
+        self.used_threads == 0
+    }
+
+    /// 尝试开始执行工作任务，返回分配给工作任务的线程数量，资源不足时返回`None`
+    ///
+    /// `pending`为还没有开始执行的工作任务数量，`pending_pixels`为这些工作任务的像素总数，都包括当前工作任务。
+    /// 没有正在执行的工作任务时总是开始执行，超过内存预算的大图像也能单独执行
+    pub fn admit(&mut self, cost: Cost, pending: usize, pending_pixels: u64) -> Option<usize> {
+        let free = self.jobs - self.used_threads;
+        if free == 0 {
+            return None;
+        }
+        if let Some(memory) = self.memory {
+            if !self.is_idle() && self.used_memory + cost.memory > memory {
+                return None;
+            }
+        }
+        let threads = if pending < free {
+            // 按像素数量的比例分配空闲的线程，剩下的工作任务每个至少保留一个线程
+            let share = free as u64 * cost.pixels / pending_pixels.max(1);
+            (share as usize).clamp(1, free - (pending - 1))
+        } else {
+            1
+        };
+        self.used_threads += threads;
+        self.used_memory += cost.memory;
+        Some(threads)
+    }
+
+    /// 从`queue`中按顺序取出第一个能够开始执行的工作任务，返回它在`costs`中的下标和分配的线程数量，
+    /// 没有空闲线程或者所有等待的工作任务都超出内存预算时返回`None`
+    ///
+    /// `queue`为等待执行的工作任务在`costs`中的下标。超出内存预算的工作任务留在原位置继续等待，
+    /// 后面内存足够的较小工作任务先开始执行，不会让空闲的线程等待大图像
+    pub fn next(&mut self, queue: &mut Vec<usize>, costs: &[Cost]) -> Option<(usize, usize)> {
+        let pending_pixels: u64 = queue.iter().map(|&index| costs[index].pixels).sum();
+        for position in 0..queue.len() {
+            if self.used_threads == self.jobs {
+                return None;
+            }
+            let index = queue[position];
+            if let Some(threads) = self.admit(costs[index], queue.len(), pending_pixels) {
+                queue.remove(position);
+                return Some((index, threads));
+            }
+        }
+        None
+    }
+
+    /// 工作任务结束，释放占用的线程和内存
+    pub fn release(&mut self, cost: Cost, threads: usize) {
+        self.used_threads -= threads;
+        self.used_memory -= cost.memory;
+    }
+}
+
+/// 按线程数量缓存的rayon线程池，分配到相同线程数量的工作任务共享同一个线程池
+static POOLS: OnceLock<Mutex<HashMap<usize, Arc<ThreadPool>>>> = OnceLock::new();
+
+/// 在`threads`个线程的rayon线程池中执行`f`，imagequant的重新映射和抖动会分到这些线程中执行
+///
+/// 每种线程数量的线程池只创建一次，之后的工作任务重复使用；
+/// 无法创建线程池时在当前线程执行，imagequant使用全局的rayon线程池
+pub fn install<F, T>(threads: usize, f: F) -> T
+where
+    F: FnOnce() -> T + Send,
+    T: Send,
+{
+    match pool(threads) {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+/// 获取`threads`个线程的线程池，第一次使用时创建
+fn pool(threads: usize) -> Option<Arc<ThreadPool>> {
+    let mut pools = POOLS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    if let Some(pool) = pools.get(&threads) {
+        return Some(pool.clone());
+    }
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(move |index| format!("remap-{}-{}", threads, index))
+        .build()
+        .ok()?;
+    let pool = Arc::new(pool);
+    pools.insert(threads, pool.clone());
+    Some(pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cost(pixels: u64) -> Cost {
+        Cost {
+            pixels,
+            memory: pixels * BYTES_PER_PIXEL,
+        }
+    }
+
+    #[test]
+    fn order_largest_first() {
+        let costs = [cost(10), cost(30), cost(20), cost(30)];
+        // 相同大小的保持目录顺序
+        assert_eq!(Strategy::Largest.order(&costs), [1, 3, 2, 0]);
+    }
+
+    #[test]
+    fn order_directory() {
+        let costs = [cost(10), cost(30), cost(20)];
+        assert_eq!(Strategy::Directory.order(&costs), [0, 1, 2]);
+        assert!(Strategy::Largest.order(&[]).is_empty());
+    }
+
+    #[test]
+    fn order_random_is_permutation() {
+        let costs: Vec<Cost> = (0..100).map(cost).collect();
+        let mut order = Strategy::Random.order(&costs);
+        order.sort();
+        assert_eq!(order, (0..100).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn admit_until_threads_used() {
+        let mut scheduler = Scheduler::new(2, None);
+        assert!(scheduler.is_idle());
+        assert_eq!(scheduler.admit(cost(10), 3, 30), Some(1));
+        assert_eq!(scheduler.admit(cost(10), 2, 20), Some(1));
+        assert_eq!(scheduler.admit(cost(10), 1, 10), None);
+        scheduler.release(cost(10), 1);
+        assert_eq!(scheduler.admit(cost(10), 1, 10), Some(1));
+    }
+
+    #[test]
+    fn admit_idle_over_memory() {
+        // 没有正在执行的工作任务时，超过内存预算的大图像也能执行
+        let mut scheduler = Scheduler::new(4, Some(100));
+        assert!(scheduler.admit(cost(1000), 2, 1001).is_some());
+        assert_eq!(scheduler.admit(cost(1), 1, 1), None);
+    }
+
+    #[test]
+    fn admit_memory_cap() {
+        let mut scheduler = Scheduler::new(4, Some(cost(100).memory));
+        assert_eq!(scheduler.admit(cost(60), 4, 160), Some(1));
+        assert_eq!(scheduler.admit(cost(60), 3, 100), None);
+        // 内存预算内的小图像仍然可以执行
+        assert_eq!(scheduler.admit(cost(40), 4, 100), Some(1));
+        scheduler.release(cost(60), 1);
+        assert_eq!(scheduler.admit(cost(60), 1, 60), Some(3));
+    }
+
+    #[test]
+    fn admit_proportional_threads() {
+        // 剩下的工作任务少于空闲线程时按像素数量比例分配线程
+        let mut scheduler = Scheduler::new(8, None);
+        assert_eq!(scheduler.admit(cost(300), 2, 400), Some(6));
+        assert_eq!(scheduler.admit(cost(100), 1, 100), Some(2));
+        assert_eq!(scheduler.admit(cost(1), 1, 1), None);
+    }
+
+    #[test]
+    fn admit_keeps_thread_for_pending() {
+        let mut scheduler = Scheduler::new(4, None);
+        // 最大的图像也要为剩下的两个工作任务各保留一个线程
+        assert_eq!(scheduler.admit(cost(1000), 3, 1002), Some(2));
+        assert_eq!(scheduler.admit(cost(1), 2, 2), Some(1));
+        assert_eq!(scheduler.admit(cost(1), 1, 1), Some(1));
+        assert!(!scheduler.is_idle());
+    }
+
+    #[test]
+    fn next_skips_jobs_over_memory() {
+        let costs = [cost(60), cost(60), cost(30), cost(10)];
+        let mut scheduler = Scheduler::new(4, Some(cost(100).memory));
+        let mut queue = vec![0, 1, 2, 3];
+        assert_eq!(scheduler.next(&mut queue, &costs), Some((0, 1)));
+        // 第二个大图像超出内存预算，后面的小图像先执行
+        assert_eq!(scheduler.next(&mut queue, &costs), Some((2, 1)));
+        assert_eq!(scheduler.next(&mut queue, &costs), Some((3, 1)));
+        assert_eq!(scheduler.next(&mut queue, &costs), None);
+        assert_eq!(queue, [1]);
+        // 释放内存后开始执行，最后一个工作任务分到所有空闲的线程
+        scheduler.release(costs[0], 1);
+        assert_eq!(scheduler.next(&mut queue, &costs), Some((1, 2)));
+        assert!(queue.is_empty());
+        assert_eq!(scheduler.next(&mut queue, &costs), None);
+    }
+
+    #[test]
+    fn reuse_pool() {
+        let first = pool(2).unwrap();
+        let second = pool(2).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(install(2, rayon::current_num_threads), 2);
+        assert_eq!(install(3, rayon::current_num_threads), 3);
+    }
+}
@@ -0,0 +1,201 @@
+/// 多尺度结构相似度每个尺度的权重，从原始尺寸开始每次缩小一半
+const SCALE_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+/// 计算结构相似度的窗口大小
+const WINDOW: usize = 8;
+/// 窗口移动的步长
+const STEP: usize = 4;
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// 单个通道的图像数据
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    /// 宽高缩小一半，每4个像素取平均值
+    fn downsample(&self) -> Plane {
+        let (width, height) = (self.width / 2, self.height / 2);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let index = y * 2 * self.width + x * 2;
+                let sum = self.data[index]
+                    + self.data[index + 1]
+                    + self.data[index + self.width]
+                    + self.data[index + self.width + 1];
+                data.push(sum / 4.0);
+            }
+        }
+        Plane {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+/// 将rgba数据拆分为预乘alpha的rgb通道和alpha通道，完全透明区域的颜色差异不影响结果
+fn planes(width: usize, height: usize, data: &[u8]) -> Vec<Plane> {
+    let mut planes: Vec<Plane> = (0..4)
+        .map(|_| Plane {
+            width,
+            height,
+            data: Vec::with_capacity(width * height),
+        })
+        .collect();
+    for p in data.chunks_exact(4) {
+        let alpha = p[3] as f32 / 255.0;
+        for channel in 0..3 {
+            planes[channel].data.push(p[channel] as f32 * alpha);
+        }
+        planes[3].data.push(p[3] as f32);
+    }
+    planes
+}
+
+/// 计算单个通道所有窗口的平均值，返回结构相似度和只包括对比度与结构部分的相似度
+fn plane_ssim(a: &Plane, b: &Plane) -> (f64, f64) {
+    let window_width = WINDOW.min(a.width);
+    let window_height = WINDOW.min(a.height);
+    let count = (window_width * window_height) as f64;
+
+    let (mut total, mut total_cs) = (0.0, 0.0);
+    let mut windows = 0;
+    for top in (0..=a.height - window_height).step_by(STEP) {
+        for left in (0..=a.width - window_width).step_by(STEP) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in top..top + window_height {
+                let row = y * a.width;
+                for x in left..left + window_width {
+                    let (va, vb) = (a.data[row + x] as f64, b.data[row + x] as f64);
+                    sum_a += va;
+                    sum_b += vb;
+                    sum_aa += va * va;
+                    sum_bb += vb * vb;
+                    sum_ab += va * vb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / count, sum_b / count);
+            let var_a = sum_aa / count - mean_a * mean_a;
+            let var_b = sum_bb / count - mean_b * mean_b;
+            let cov = sum_ab / count - mean_a * mean_b;
+            let luminance = (2.0 * mean_a * mean_b + C1) / (mean_a * mean_a + mean_b * mean_b + C1);
+            let cs = (2.0 * cov + C2) / (var_a + var_b + C2);
+            total += luminance * cs;
+            total_cs += cs;
+            windows += 1;
+        }
+    }
+    (total / windows as f64, total_cs / windows as f64)
+}
+
+/// 计算两张相同尺寸rgba图像的多尺度结构相似度(MS-SSIM)，`1.0`为完全相同
+///
+/// rgb通道预乘alpha后和alpha通道一起计算，每个尺度取4个通道的平均值。
+/// 与MS-SSIM的定义一样，前面的尺度只使用对比度与结构部分，最后一个尺度使用完整的结构相似度，
+/// 按尺度权重取幂后相乘；图像缩小到不足一个窗口时停止，权重按实际使用的尺度重新归一化。
+/// 窗口为8x8的均匀窗口而不是高斯窗口，结果与参考实现相近但不完全相同
+pub fn ssim(width: u32, height: u32, a: &[u8], b: &[u8]) -> f64 {
+    let (width, height) = (width as usize, height as usize);
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+    let mut planes_a = planes(width, height, a);
+    let mut planes_b = planes(width, height, b);
+
+    // 每个尺度的(结构相似度, 对比度与结构相似度, 权重)
+    let mut scales = Vec::with_capacity(SCALE_WEIGHTS.len());
+    for (scale, weight) in SCALE_WEIGHTS.iter().enumerate() {
+        if scale > 0 {
+            if planes_a[0].width < WINDOW * 2 || planes_a[0].height < WINDOW * 2 {
+                break;
+            }
+            planes_a = planes_a.iter().map(Plane::downsample).collect();
+            planes_b = planes_b.iter().map(Plane::downsample).collect();
+        }
+        let (total, total_cs) = planes_a
+            .iter()
+            .zip(planes_b.iter())
+            .map(|(a, b)| plane_ssim(a, b))
+            .fold((0.0, 0.0), |acc, value| (acc.0 + value.0, acc.1 + value.1));
+        let channels = planes_a.len() as f64;
+        scales.push((total / channels, total_cs / channels, *weight));
+    }
+
+    let total_weight: f64 = scales.iter().map(|scale| scale.2).sum();
+    let last = scales.len() - 1;
+    scales
+        .iter()
+        .enumerate()
+        .map(|(index, &(value, cs, weight))| {
+            // 负的相关性按0处理，避免取幂后出现NaN
+            let value = if index == last { value } else { cs };
+            value.max(0.0).powf(weight / total_weight)
+        })
+        .product()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 64x64的渐变图像
+    fn gradient(alpha: u8) -> Vec<u8> {
+        let mut data = Vec::with_capacity(64 * 64 * 4);
+        for y in 0..64u32 {
+            for x in 0..64u32 {
+                data.extend_from_slice(&[(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8, alpha]);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn identical_images() {
+        let a = gradient(255);
+        assert_eq!(ssim(64, 64, &a, &a), 1.0);
+        // 小于一个窗口的图像
+        assert_eq!(ssim(2, 1, &a[..8], &a[..8]), 1.0);
+    }
+
+    #[test]
+    fn degraded_image_scores_lower() {
+        let a = gradient(255);
+        // 颜色量化到更少的级别，误差越大相似度越低
+        let quantize = |step: u8| -> Vec<u8> {
+            a.chunks_exact(4)
+                .flat_map(|p| {
+                    [
+                        p[0] / step * step,
+                        p[1] / step * step,
+                        p[2] / step * step,
+                        p[3],
+                    ]
+                })
+                .collect()
+        };
+        let light = ssim(64, 64, &a, &quantize(16));
+        let heavy = ssim(64, 64, &a, &quantize(64));
+        assert!(light < 1.0);
+        assert!(heavy < light);
+    }
+
+    #[test]
+    fn alpha_differences_count() {
+        // rgb为黑色，预乘alpha后rgb通道完全相同，只有alpha通道不同
+        let a: Vec<u8> = (0..64 * 64)
+            .flat_map(|index| [0, 0, 0, (index % 64 * 4) as u8])
+            .collect();
+        let b: Vec<u8> = (0..64 * 64)
+            .flat_map(|index| [0, 0, 0, (index / 64 * 4) as u8])
+            .collect();
+        assert!(ssim(64, 64, &a, &b) < 0.9);
+
+        // 完全透明区域的颜色差异不影响结果
+        assert_eq!(ssim(64, 64, &gradient(0), &vec![0; 64 * 64 * 4]), 1.0);
+    }
+}
@@ -1,6 +1,8 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::mpsc;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, PoisonError};
 use std::thread;
 
 enum Message {
@@ -14,7 +16,13 @@ pub struct ThreadPool {
     job_sender: mpsc::Sender<Message>,
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+/// 执行任务并报告结果，任务panic时返回`true`
+type Job = Box<dyn FnOnce() -> bool + Send + 'static>;
+
+/// 任务的执行结果，任务panic时为panic的信息
+pub type JobResult<T> = Result<T, String>;
+
+type JobReceiver = Arc<Mutex<mpsc::Receiver<Message>>>;
 
 impl ThreadPool {
     /// 创建线程池。
@@ -45,57 +53,174 @@ impl ThreadPool {
     }
 
     // 需要在多线程中执行的闭包函数
-    pub fn execute<F>(&self, f: F)
+    //
+    // 闭包执行结束后在同一线程中调用`report`报告返回值，闭包panic时不会影响其他任务，
+    // `report`收到panic的信息，用于把panic报告为任务失败；
+    // `report`自身panic时同样捕获并替换线程，这个任务的结果不再报告
+    pub fn execute<F, T, R>(&self, f: F, report: R)
     where
-        F: FnOnce() + Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+        R: FnOnce(JobResult<T>) + Send + 'static,
     {
-        let job = Box::new(f);
+        let job = Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f))
+                .map_err(|payload| panic_message(payload.as_ref()));
+            let panicked = result.is_err();
+            let report_panicked = panic::catch_unwind(AssertUnwindSafe(|| report(result))).is_err();
+            panicked || report_panicked
+        });
         self.job_sender.send(Message::NewJob(job)).unwrap();
     }
 }
 
+/// 取出panic的信息，`panic!`的参数不是字符串时返回默认信息
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
 /// 工作任务
 #[derive(Debug)]
 struct Worker {
     /// 工作线程id
+    #[allow(dead_code)]
     id: usize,
-    /// 保存创建的线程
-    thread: Option<thread::JoinHandle<()>>,
+    /// 保存创建的线程，任务panic后替换为新创建的线程
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            // 锁定接受者对象用于获取数据，尝试等待此接收者上的值阻塞当前线程，自动分配线程池的核心功能
-            let message = receiver.lock().unwrap().recv().unwrap();
-
-            match message {
-                // 工作消息执行工作
-                Message::NewJob(job) => {
-                    job();
-                }
-                // 关闭线程消息
-                Message::Terminate => break,
-            }
-        });
-        Worker {
-            id,
-            thread: Some(thread),
-        }
+    fn new(id: usize, receiver: JobReceiver) -> Worker {
+        let thread = Arc::new(Mutex::new(None));
+        Worker::spawn(id, receiver, Arc::clone(&thread));
+        Worker { id, thread }
+    }
+
+    /// 创建工作线程并保存到`slot`
+    ///
+    /// 任务panic时捕获panic并报告，之后线程的状态不再可靠，创建新的线程替换当前线程
+    fn spawn(id: usize, receiver: JobReceiver, slot: Arc<Mutex<Option<thread::JoinHandle<()>>>>) {
+        // 保存线程前持有锁，新线程panic后替换线程时要等待保存完成
+        let mut handle = slot.lock().unwrap_or_else(PoisonError::into_inner);
+        let worker_slot = Arc::clone(&slot);
+        // 线程名称包含工作线程id，panic信息中可以看到是哪个线程
+        let builder = thread::Builder::new().name(format!("worker-{}", id));
+        *handle = Some(
+            builder
+                .spawn(move || loop {
+                    // 锁定接受者对象用于获取数据，尝试等待此接收者上的值阻塞当前线程，自动分配线程池的核心功能
+                    let message = receiver
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv();
+
+                    match message {
+                        // 工作消息执行工作
+                        Ok(Message::NewJob(job)) => {
+                            if job() {
+                                Worker::spawn(id, receiver, worker_slot);
+                                break;
+                            }
+                        }
+                        // 关闭线程消息，线程池已经释放时同样结束线程
+                        Ok(Message::Terminate) | Err(_) => break,
+                    }
+                })
+                .unwrap(),
+        );
     }
 }
 
 impl Drop for ThreadPool {
     // 在清理数据时结束线程
     fn drop(&mut self) {
+        // 线程已经结束时接收端可能已经关闭，发送失败不影响其他线程结束
         for _ in &self.workers {
-            self.job_sender.send(Message::Terminate).unwrap();
+            let _ = self.job_sender.send(Message::Terminate);
         }
 
         for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            // 等待的线程panic后会替换为新的线程，直到没有新的线程
+            loop {
+                let thread = worker
+                    .thread
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .take();
+                match thread {
+                    Some(thread) => {
+                        let _ = thread.join();
+                    }
+                    None => break,
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// 等待任务报告结果，超时时测试失败，不会一直等待
+    fn recv<T>(receiver: &mpsc::Receiver<T>) -> T {
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap()
+    }
+
+    #[test]
+    fn panic_is_reported() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(
+            || -> u32 { panic!("broken frame") },
+            move |result| sender.send(result).unwrap(),
+        );
+        assert_eq!(recv(&receiver), Err("broken frame".to_string()));
+    }
+
+    #[test]
+    fn jobs_run_after_respawn() {
+        // 只有一个线程，panic之后的任务由替换的线程执行
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        let panic_sender = sender.clone();
+        pool.execute(
+            || -> u32 { panic!("{}", 1) },
+            move |result| panic_sender.send(result).unwrap(),
+        );
+        // `report`自身panic时同样替换线程
+        pool.execute(|| 2, |_| panic!("report"));
+        for value in 3..6 {
+            let sender = sender.clone();
+            pool.execute(move || value, move |result| sender.send(result).unwrap());
+        }
+        assert_eq!(recv(&receiver), Err("1".to_string()));
+        for value in 3..6 {
+            assert_eq!(recv(&receiver), Ok(value));
+        }
+    }
+
+    #[test]
+    fn drop_after_panics() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..4 {
+            let sender = sender.clone();
+            pool.execute(
+                || -> u32 { panic!("job") },
+                move |result| sender.send(result).unwrap(),
+            );
+        }
+        for _ in 0..4 {
+            assert!(recv(&receiver).is_err());
+        }
+        // 所有线程都被替换过，释放线程池时等待替换的线程结束
+        drop(pool);
+    }
+}